@@ -0,0 +1,773 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::storage::{Entity, Group, KillmailReader, Level, Side, StoredKillmail, StoredParticipant};
+
+/// The periods of the kills and losses counters, in days
+pub const PERIODS: [i64; 3] = [30, 60, 90];
+
+/// The lower bound of the killmail time `days` before `now`, as stored in the database
+pub fn since(now: DateTime<Utc>, days: i64) -> String {
+    (now - Duration::days(days)).format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// An entity with the name resolved by ESI, if any
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+pub struct Named {
+    pub id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+impl Named {
+    pub fn new(id: i32) -> Self {
+        Self { id, name: None }
+    }
+
+    fn set_name(&mut self, names: &HashMap<i32, String>) {
+        self.name = names.get(&self.id).cloned();
+    }
+}
+impl fmt::Display for Named {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{}", name),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// The part of the killmails an entity appears in
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct Share {
+    #[serde(flatten)]
+    pub entity: Named,
+    pub count: usize,
+    pub percent: f64,
+}
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:.0}%)", self.entity, self.percent)
+    }
+}
+
+/// The kills and losses of the last `days`
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+pub struct Activity {
+    pub days: i64,
+    pub kills: usize,
+    pub losses: usize,
+}
+
+/// The most frequent ids first, at most `top` of them
+fn shares(counts: HashMap<i32, usize>, total: usize, top: usize) -> Vec<Share> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+        .into_iter()
+        .take(top)
+        .map(|(id, count)| Share {
+            entity: Named::new(id),
+            count,
+            percent: (count as f64 * 1000.0 / total.max(1) as f64).round() / 10.0,
+        })
+        .collect()
+}
+
+fn count<I: IntoIterator<Item = i32>>(counts: &mut HashMap<i32, usize>, ids: I) {
+    for id in ids {
+        *counts.entry(id).or_insert(0) += 1;
+    }
+}
+
+fn write_shares(f: &mut fmt::Formatter, title: &str, shares: &[Share]) -> fmt::Result {
+    let shares: Vec<_> = shares.iter().map(|share| share.to_string()).collect();
+    let shares = if shares.is_empty() { String::from("-") } else { shares.join(", ") };
+    writeln!(f, "{:<23}{}", title, shares)
+}
+
+fn write_activity(f: &mut fmt::Formatter, activity: &[Activity]) -> fmt::Result {
+    let counts = |value: fn(&Activity) -> usize| -> String {
+        let counts: Vec<_> = activity
+            .iter()
+            .map(|activity| format!("{} ({} days)", value(activity), activity.days))
+            .collect();
+        counts.join(", ")
+    };
+    writeln!(f, "{:<23}{}", "Kills:", counts(|activity| activity.kills))?;
+    writeln!(f, "{:<23}{}", "Losses:", counts(|activity| activity.losses))
+}
+
+/// The kills and losses of every period of `PERIODS`
+fn activity<F>(killmails: &[StoredKillmail], now: DateTime<Utc>, is_loss: F) -> Vec<Activity>
+where
+    F: Fn(&StoredKillmail) -> bool,
+{
+    PERIODS
+        .iter()
+        .map(|&period| {
+            let since = since(now, period);
+            let (losses, kills): (Vec<_>, Vec<_>) = killmails
+                .iter()
+                .filter(|killmail| killmail.killmail_time >= since)
+                .partition(|killmail| is_loss(killmail));
+            Activity {
+                days: period,
+                kills: kills.len(),
+                losses: losses.len(),
+            }
+        })
+        .collect()
+}
+
+/// A profile that can be printed as the text or JSON, with the ids ESI resolves to names
+pub trait Report: Serialize + fmt::Display {
+    /// The name of the character, corporation or alliance, the id until the names are set
+    fn title(&self) -> String;
+
+    /// The ids of the characters, corporations, alliances, ship types and systems of the report
+    fn ids(&self) -> Vec<i32>;
+
+    fn set_names(&mut self, names: &HashMap<i32, String>);
+}
+
+/// The character profile sketched in `doc/schema.txt`
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct CharacterProfile {
+    pub character: Named,
+    pub corporation: Option<Named>,
+    pub alliance: Option<Named>,
+    pub activity: Vec<Activity>,
+    /// The number of days the shares below are based on
+    pub days: i64,
+    pub ships: Vec<Share>,
+    pub systems: Vec<Share>,
+    pub friendly_characters: Vec<Share>,
+    pub friendly_corporations: Vec<Share>,
+    pub friendly_alliances: Vec<Share>,
+}
+impl CharacterProfile {
+    /// Builds the profile from the killmails of the character, oldest first, that cover the longest
+    /// of `PERIODS` and `days`. The corporation and the alliance are taken from the latest killmail.
+    pub fn build(character_id: i32, killmails: &[StoredKillmail], now: DateTime<Utc>, days: i64, top: usize) -> Self {
+        let own = |killmail: &'_ StoredKillmail| -> Vec<StoredParticipant> {
+            killmail
+                .participants
+                .iter()
+                .filter(|participant| participant.character_id == Some(character_id))
+                .cloned()
+                .collect()
+        };
+        let is_loss = |killmail: &StoredKillmail| own(killmail).iter().any(|participant| participant.is_victim);
+
+        let activity = activity(killmails, now, is_loss);
+
+        let since = since(now, days);
+        let window: Vec<_> = killmails.iter().filter(|killmail| killmail.killmail_time >= since).collect();
+        let last = killmails.last().and_then(|killmail| own(killmail).into_iter().next());
+        let corporation_id = last.as_ref().and_then(|participant| participant.corporation_id);
+        let alliance_id = last.as_ref().and_then(|participant| participant.alliance_id);
+
+        let mut ships = HashMap::new();
+        let mut systems = HashMap::new();
+        let mut characters = HashMap::new();
+        let mut corporations = HashMap::new();
+        let mut alliances = HashMap::new();
+        let mut kills = 0;
+        for killmail in &window {
+            let participants = own(killmail);
+            count(&mut ships, participants.iter().find_map(|participant| participant.ship_type_id));
+            count(&mut systems, Some(killmail.solar_system_id));
+            if participants.iter().any(|participant| participant.is_victim) {
+                continue;
+            }
+            kills += 1;
+            let friends: Vec<_> = killmail.attackers().collect();
+            let distinct = |id: fn(&StoredParticipant) -> Option<i32>, skip: Option<i32>| -> BTreeSet<i32> {
+                friends.iter().filter_map(|friend| id(friend)).filter(|id| Some(*id) != skip).collect()
+            };
+            count(&mut characters, distinct(|friend| friend.character_id, Some(character_id)));
+            count(&mut corporations, distinct(|friend| friend.corporation_id, corporation_id));
+            count(&mut alliances, distinct(|friend| friend.alliance_id, alliance_id));
+        }
+        let ships_total = ships.values().sum();
+
+        Self {
+            character: Named::new(character_id),
+            corporation: corporation_id.map(Named::new),
+            alliance: alliance_id.map(Named::new),
+            activity,
+            days,
+            ships: shares(ships, ships_total, top),
+            systems: shares(systems, window.len(), top),
+            friendly_characters: shares(characters, kills, top),
+            friendly_corporations: shares(corporations, kills, top),
+            friendly_alliances: shares(alliances, kills, top),
+        }
+    }
+
+    fn lists(&self) -> [&Vec<Share>; 5] {
+        [
+            &self.ships,
+            &self.systems,
+            &self.friendly_characters,
+            &self.friendly_corporations,
+            &self.friendly_alliances,
+        ]
+    }
+
+    fn lists_mut(&mut self) -> [&mut Vec<Share>; 5] {
+        [
+            &mut self.ships,
+            &mut self.systems,
+            &mut self.friendly_characters,
+            &mut self.friendly_corporations,
+            &mut self.friendly_alliances,
+        ]
+    }
+
+}
+impl Report for CharacterProfile {
+    fn title(&self) -> String {
+        self.character.to_string()
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        let mut ids: BTreeSet<i32> = self.lists().iter().flat_map(|list| list.iter()).map(|share| share.entity.id).collect();
+        ids.insert(self.character.id);
+        ids.extend(self.corporation.iter().chain(self.alliance.iter()).map(|named| named.id));
+        ids.into_iter().collect()
+    }
+
+    fn set_names(&mut self, names: &HashMap<i32, String>) {
+        self.character.set_name(names);
+        for named in self.corporation.iter_mut().chain(self.alliance.iter_mut()) {
+            named.set_name(names);
+        }
+        for list in self.lists_mut() {
+            for share in list.iter_mut() {
+                share.entity.set_name(names);
+            }
+        }
+    }
+}
+impl fmt::Display for CharacterProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let optional = |named: &Option<Named>| named.as_ref().map_or(String::from("-"), |named| named.to_string());
+        writeln!(f, "{:<23}{}", "Character:", self.character)?;
+        writeln!(f, "{:<23}{}", "Corporation:", optional(&self.corporation))?;
+        writeln!(f, "{:<23}{}", "Alliance:", optional(&self.alliance))?;
+        write_activity(f, &self.activity)?;
+        writeln!(f, "Last {} days", self.days)?;
+        write_shares(f, "Probable Ship:", &self.ships)?;
+        write_shares(f, "Activity System:", &self.systems)?;
+        write_shares(f, "Friendly Characters:", &self.friendly_characters)?;
+        write_shares(f, "Friendly Corporations:", &self.friendly_corporations)?;
+        write_shares(f, "Friendly Alliances:", &self.friendly_alliances)
+    }
+}
+
+/// The kills and losses of one day
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+pub struct Daily {
+    pub day: String,
+    pub kills: usize,
+    pub losses: usize,
+}
+
+/// The profile of a corporation or an alliance, built from the killmails of its members
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct GroupProfile {
+    pub kind: &'static str,
+    pub entity: Named,
+    /// The alliance of a corporation, taken from the latest killmail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alliance: Option<Named>,
+    pub activity: Vec<Activity>,
+    /// The number of days the values below are based on
+    pub days: i64,
+    /// The days with kills or losses, oldest first
+    pub daily: Vec<Daily>,
+    /// The number of the members seen on the killmails
+    pub active_members: usize,
+    pub top_pilots: Vec<Share>,
+    pub ships: Vec<Share>,
+    pub systems: Vec<Share>,
+    pub allied_corporations: Vec<Share>,
+    pub allied_alliances: Vec<Share>,
+    pub hostile_corporations: Vec<Share>,
+    pub hostile_alliances: Vec<Share>,
+}
+impl GroupProfile {
+    /// Builds the profile from the killmails of the members, oldest first, that cover the longest
+    /// of `PERIODS` and `days`. A killmail is a loss if the victim is a member, otherwise a kill.
+    /// The allies are the other corporations and alliances on the kills, the hostiles are
+    /// the victims of the kills and the attackers of the losses.
+    pub fn build(entity: Entity, killmails: &[StoredKillmail], now: DateTime<Utc>, days: i64, top: usize) -> Self {
+        let is_loss = |killmail: &StoredKillmail| killmail.victim().is_some_and(|victim| entity.matches(victim));
+        let activity = activity(killmails, now, is_loss);
+
+        let since = since(now, days);
+        let window: Vec<_> = killmails.iter().filter(|killmail| killmail.killmail_time >= since).collect();
+        let alliance_id = match entity {
+            Entity::Corporation(_) => killmails.last().and_then(|killmail| {
+                killmail
+                    .participants
+                    .iter()
+                    .find(|participant| entity.matches(participant))
+                    .and_then(|participant| participant.alliance_id)
+            }),
+            _ => None,
+        };
+
+        let mut daily: Vec<Daily> = Vec::new();
+        let mut members = BTreeSet::new();
+        let mut pilots = HashMap::new();
+        let mut ships = HashMap::new();
+        let mut systems = HashMap::new();
+        let mut allied_corporations = HashMap::new();
+        let mut allied_alliances = HashMap::new();
+        let mut hostile_corporations = HashMap::new();
+        let mut hostile_alliances = HashMap::new();
+        let mut kills = 0;
+        for killmail in &window {
+            let loss = is_loss(killmail);
+            let day = &killmail.killmail_time[..10.min(killmail.killmail_time.len())];
+            if daily.last().is_none_or(|last| last.day != day) {
+                daily.push(Daily {
+                    day: day.to_owned(),
+                    kills: 0,
+                    losses: 0,
+                });
+            }
+            if let Some(last) = daily.last_mut() {
+                if loss {
+                    last.losses += 1;
+                } else {
+                    last.kills += 1;
+                }
+            }
+            count(&mut systems, Some(killmail.solar_system_id));
+
+            let (own, others): (Vec<_>, Vec<_>) = killmail.participants.iter().partition(|participant| entity.matches(participant));
+            members.extend(own.iter().filter_map(|member| member.character_id));
+            count(&mut ships, own.iter().filter_map(|member| member.ship_type_id));
+            let distinct = |participants: &[&StoredParticipant], id: fn(&StoredParticipant) -> Option<i32>| -> BTreeSet<i32> {
+                participants.iter().filter_map(|participant| id(participant)).collect()
+            };
+            let (allies, hostiles): (Vec<_>, Vec<_>) = others.into_iter().partition(|other| !loss && !other.is_victim);
+            count(&mut hostile_corporations, distinct(&hostiles, |hostile| hostile.corporation_id));
+            count(&mut hostile_alliances, distinct(&hostiles, |hostile| hostile.alliance_id));
+            if loss {
+                continue;
+            }
+            kills += 1;
+            let attackers: BTreeSet<_> = own
+                .iter()
+                .filter(|member| !member.is_victim)
+                .filter_map(|member| member.character_id)
+                .collect();
+            count(&mut pilots, attackers);
+            count(&mut allied_corporations, distinct(&allies, |ally| ally.corporation_id));
+            // The own alliance of a corporation is not an ally
+            let alliances = distinct(&allies, |ally| ally.alliance_id);
+            count(&mut allied_alliances, alliances.into_iter().filter(|id| Some(*id) != alliance_id));
+        }
+        let ships_total = ships.values().sum();
+
+        Self {
+            kind: entity.kind(),
+            entity: Named::new(entity.id()),
+            alliance: alliance_id.map(Named::new),
+            activity,
+            days,
+            daily,
+            active_members: members.len(),
+            top_pilots: shares(pilots, kills, top),
+            ships: shares(ships, ships_total, top),
+            systems: shares(systems, window.len(), top),
+            allied_corporations: shares(allied_corporations, kills, top),
+            allied_alliances: shares(allied_alliances, kills, top),
+            hostile_corporations: shares(hostile_corporations, window.len(), top),
+            hostile_alliances: shares(hostile_alliances, window.len(), top),
+        }
+    }
+
+    fn lists(&self) -> [&Vec<Share>; 7] {
+        [
+            &self.top_pilots,
+            &self.ships,
+            &self.systems,
+            &self.allied_corporations,
+            &self.allied_alliances,
+            &self.hostile_corporations,
+            &self.hostile_alliances,
+        ]
+    }
+
+    fn lists_mut(&mut self) -> [&mut Vec<Share>; 7] {
+        [
+            &mut self.top_pilots,
+            &mut self.ships,
+            &mut self.systems,
+            &mut self.allied_corporations,
+            &mut self.allied_alliances,
+            &mut self.hostile_corporations,
+            &mut self.hostile_alliances,
+        ]
+    }
+}
+impl Report for GroupProfile {
+    fn title(&self) -> String {
+        self.entity.to_string()
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        let mut ids: BTreeSet<i32> = self.lists().iter().flat_map(|list| list.iter()).map(|share| share.entity.id).collect();
+        ids.insert(self.entity.id);
+        ids.extend(self.alliance.iter().map(|named| named.id));
+        ids.into_iter().collect()
+    }
+
+    fn set_names(&mut self, names: &HashMap<i32, String>) {
+        self.entity.set_name(names);
+        for named in self.alliance.iter_mut() {
+            named.set_name(names);
+        }
+        for list in self.lists_mut() {
+            for share in list.iter_mut() {
+                share.entity.set_name(names);
+            }
+        }
+    }
+}
+impl fmt::Display for GroupProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut kind = self.kind.to_owned();
+        kind[..1].make_ascii_uppercase();
+        writeln!(f, "{:<23}{}", format!("{}:", kind), self.entity)?;
+        if let Some(ref alliance) = self.alliance {
+            writeln!(f, "{:<23}{}", "Alliance:", alliance)?;
+        }
+        write_activity(f, &self.activity)?;
+        writeln!(f, "Last {} days", self.days)?;
+        writeln!(f, "{:<23}{}", "Active Members:", self.active_members)?;
+        write_shares(f, "Top Pilots:", &self.top_pilots)?;
+        write_shares(f, "Preferred Ships:", &self.ships)?;
+        write_shares(f, "Home Systems:", &self.systems)?;
+        write_shares(f, "Allied Corporations:", &self.allied_corporations)?;
+        write_shares(f, "Allied Alliances:", &self.allied_alliances)?;
+        write_shares(f, "Hostile Corporations:", &self.hostile_corporations)?;
+        write_shares(f, "Hostile Alliances:", &self.hostile_alliances)?;
+        writeln!(f, "{:<23}Kills Losses", "Per Day:")?;
+        for daily in &self.daily {
+            writeln!(f, "{:<23}{:>5} {:>6}", daily.day, daily.kills, daily.losses)?;
+        }
+        Ok(())
+    }
+}
+
+/// Who the entity kills most and who kills it most, from the victims of its kills
+/// and the attackers on its losses over the last `days`
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct Opponents {
+    pub kind: &'static str,
+    pub entity: Named,
+    pub days: i64,
+    pub kills: usize,
+    pub losses: usize,
+    /// The shares of the kills
+    pub victim_characters: Vec<Share>,
+    pub victim_corporations: Vec<Share>,
+    pub victim_alliances: Vec<Share>,
+    /// The shares of the losses
+    pub attacker_characters: Vec<Share>,
+    pub attacker_corporations: Vec<Share>,
+    pub attacker_alliances: Vec<Share>,
+    pub attacker_ships: Vec<Share>,
+}
+impl Opponents {
+    /// Queries the `top` opponents of every level since `days` before `now`
+    pub fn query(
+        reader: &mut dyn KillmailReader,
+        entity: Entity,
+        now: DateTime<Utc>,
+        days: i64,
+        top: usize,
+    ) -> anyhow::Result<Self> {
+        let since = since(now, days);
+        let times = reader.killmail_times(entity, &since)?;
+        let losses = times.iter().filter(|(_, is_loss)| *is_loss).count();
+        let kills = times.len() - losses;
+        let mut opponents = |side: Side, group: Group| -> anyhow::Result<Vec<Share>> {
+            let total = match side {
+                Side::Victim => kills,
+                Side::Attacker => losses,
+            };
+            let counts = reader.opponents(entity, side, group, &since, top)?;
+            Ok(shares(counts.into_iter().collect(), total, top))
+        };
+        Ok(Self {
+            kind: entity.kind(),
+            entity: Named::new(entity.id()),
+            days,
+            kills,
+            losses,
+            victim_characters: opponents(Side::Victim, Group::Level(Level::Character))?,
+            victim_corporations: opponents(Side::Victim, Group::Level(Level::Corporation))?,
+            victim_alliances: opponents(Side::Victim, Group::Level(Level::Alliance))?,
+            attacker_characters: opponents(Side::Attacker, Group::Level(Level::Character))?,
+            attacker_corporations: opponents(Side::Attacker, Group::Level(Level::Corporation))?,
+            attacker_alliances: opponents(Side::Attacker, Group::Level(Level::Alliance))?,
+            attacker_ships: opponents(Side::Attacker, Group::ShipType)?,
+        })
+    }
+
+    fn lists(&self) -> [&Vec<Share>; 7] {
+        [
+            &self.victim_characters,
+            &self.victim_corporations,
+            &self.victim_alliances,
+            &self.attacker_characters,
+            &self.attacker_corporations,
+            &self.attacker_alliances,
+            &self.attacker_ships,
+        ]
+    }
+
+    fn lists_mut(&mut self) -> [&mut Vec<Share>; 7] {
+        [
+            &mut self.victim_characters,
+            &mut self.victim_corporations,
+            &mut self.victim_alliances,
+            &mut self.attacker_characters,
+            &mut self.attacker_corporations,
+            &mut self.attacker_alliances,
+            &mut self.attacker_ships,
+        ]
+    }
+}
+impl Report for Opponents {
+    fn title(&self) -> String {
+        self.entity.to_string()
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        let mut ids: BTreeSet<i32> = self
+            .lists()
+            .iter()
+            .flat_map(|list| list.iter())
+            .map(|share| share.entity.id)
+            .collect();
+        ids.insert(self.entity.id);
+        ids.into_iter().collect()
+    }
+
+    fn set_names(&mut self, names: &HashMap<i32, String>) {
+        self.entity.set_name(names);
+        for list in self.lists_mut() {
+            for share in list.iter_mut() {
+                share.entity.set_name(names);
+            }
+        }
+    }
+}
+impl fmt::Display for Opponents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut kind = self.kind.to_owned();
+        kind[..1].make_ascii_uppercase();
+        writeln!(f, "{:<23}{}", format!("{}:", kind), self.entity)?;
+        writeln!(f, "Last {} days", self.days)?;
+        writeln!(f, "{:<23}{}", "Kills:", self.kills)?;
+        write_shares(f, "Killed Pilots:", &self.victim_characters)?;
+        write_shares(f, "Killed Corporations:", &self.victim_corporations)?;
+        write_shares(f, "Killed Alliances:", &self.victim_alliances)?;
+        writeln!(f, "{:<23}{}", "Losses:", self.losses)?;
+        write_shares(f, "Killer Pilots:", &self.attacker_characters)?;
+        write_shares(f, "Killer Corporations:", &self.attacker_corporations)?;
+        write_shares(f, "Killer Alliances:", &self.attacker_alliances)?;
+        write_shares(f, "Ships Used Against:", &self.attacker_ships)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use crate::storage::{StoredKillmail, StoredParticipant};
+    use chrono::{DateTime, TimeZone, Utc};
+
+    pub fn now() -> DateTime<Utc> {
+        Utc.ymd(2022, 1, 31).and_hms(12, 0, 0)
+    }
+
+    /// (character, corporation, alliance, ship)
+    pub type Pilot = (i32, i32, Option<i32>, i32);
+
+    pub fn killmail(id: i32, time: &str, system: i32, victim: Pilot, attackers: &[Pilot]) -> StoredKillmail {
+        let participant = |(character, corporation, alliance, ship): Pilot, is_victim| StoredParticipant {
+            character_id: Some(character),
+            corporation_id: Some(corporation),
+            alliance_id: alliance,
+            ship_type_id: Some(ship),
+            damage: 100,
+            is_victim,
+        };
+        let mut participants = vec![participant(victim, true)];
+        participants.extend(attackers.iter().map(|attacker| participant(*attacker, false)));
+        StoredKillmail {
+            killmail_id: id,
+            killmail_time: String::from(time),
+            solar_system_id: system,
+            total_value: Some(1_000_000.0 * id as f64),
+            participants,
+        }
+    }
+
+    pub const SEB: Pilot = (1, 10, Some(100), 29990);
+    pub const ALEX: Pilot = (2, 10, Some(100), 17738);
+    pub const SMOCK: Pilot = (3, 20, Some(200), 29990);
+    pub const ENEMY: Pilot = (4, 30, None, 587);
+    pub const OTHER: Pilot = (5, 40, Some(400), 11176);
+
+    /// The killmails of SEB, oldest first
+    pub fn killmails() -> Vec<StoredKillmail> {
+        vec![
+            // Out of the 60 days
+            killmail(1, "2021-11-10T10:00:00Z", 30000142, ENEMY, &[SEB, OTHER]),
+            killmail(2, "2022-01-10T10:00:00Z", 30002187, ENEMY, &[SEB, ALEX, SMOCK]),
+            killmail(3, "2022-01-20T20:00:00Z", 30002187, OTHER, &[ALEX, SEB]),
+            killmail(4, "2022-01-29T21:00:00Z", 30000142, SEB, &[ENEMY, OTHER]),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+    use crate::storage::fixtures::store;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_since() {
+        assert_eq!(since(now(), 30), "2022-01-01T12:00:00Z");
+    }
+
+    #[test]
+    fn test_character_profile() {
+        let profile = CharacterProfile::build(1, &killmails(), now(), 60, 5);
+
+        assert_eq!(profile.corporation, Some(Named::new(10)));
+        assert_eq!(profile.alliance, Some(Named::new(100)));
+        let activity: Vec<_> = profile.activity.iter().map(|a| (a.days, a.kills, a.losses)).collect();
+        assert_eq!(activity, vec![(30, 2, 1), (60, 2, 1), (90, 3, 1)]);
+
+        let ids = |shares: &[Share]| shares.iter().map(|share| (share.entity.id, share.count)).collect::<Vec<_>>();
+        assert_eq!(ids(&profile.ships), vec![(29990, 3)]);
+        assert_eq!(ids(&profile.systems), vec![(30002187, 2), (30000142, 1)]);
+        assert_eq!(profile.systems[0].percent, 66.7);
+        assert_eq!(ids(&profile.friendly_characters), vec![(2, 2), (3, 1)]);
+        assert_eq!(profile.friendly_characters[0].percent, 100.0);
+        // The own corporation and alliance are not friends
+        assert_eq!(ids(&profile.friendly_corporations), vec![(20, 1)]);
+        assert_eq!(ids(&profile.friendly_alliances), vec![(200, 1)]);
+    }
+
+    #[test]
+    fn test_character_profile_top_and_names() {
+        let mut profile = CharacterProfile::build(1, &killmails(), now(), 90, 1);
+        assert_eq!(profile.systems.len(), 1);
+        assert_eq!(profile.ids(), vec![1, 2, 10, 20, 100, 200, 29990, 30000142]);
+
+        let names: HashMap<_, _> = vec![(1, String::from("Seb Odessa")), (29990, String::from("Loki"))].into_iter().collect();
+        profile.set_names(&names);
+        let text = profile.to_string();
+        assert!(text.contains("Character:             Seb Odessa\n"));
+        assert!(text.contains("Kills:                 2 (30 days), 2 (60 days), 3 (90 days)\n"));
+        assert!(text.contains("Probable Ship:         Loki (100%)\n"));
+
+        let json = serde_json::to_value(&profile).unwrap();
+        assert_eq!(json["character"]["name"], "Seb Odessa");
+        assert_eq!(json["ships"][0]["id"], 29990);
+        assert!(json["systems"][0].get("name").is_none());
+    }
+
+    #[test]
+    fn test_corporation_profile() {
+        let profile = GroupProfile::build(Entity::Corporation(10), &killmails(), now(), 60, 5);
+
+        assert_eq!(profile.kind, "corporation");
+        assert_eq!(profile.alliance, Some(Named::new(100)));
+        let activity: Vec<_> = profile.activity.iter().map(|a| (a.days, a.kills, a.losses)).collect();
+        assert_eq!(activity, vec![(30, 2, 1), (60, 2, 1), (90, 3, 1)]);
+        let daily: Vec<_> = profile.daily.iter().map(|d| (d.day.as_str(), d.kills, d.losses)).collect();
+        assert_eq!(daily, vec![("2022-01-10", 1, 0), ("2022-01-20", 1, 0), ("2022-01-29", 0, 1)]);
+        assert_eq!(profile.active_members, 2);
+
+        let ids = |shares: &[Share]| shares.iter().map(|share| (share.entity.id, share.count)).collect::<Vec<_>>();
+        assert_eq!(ids(&profile.top_pilots), vec![(1, 2), (2, 2)]);
+        assert_eq!(profile.top_pilots[0].percent, 100.0);
+        assert_eq!(ids(&profile.ships), vec![(29990, 3), (17738, 2)]);
+        assert_eq!(ids(&profile.systems), vec![(30002187, 2), (30000142, 1)]);
+        assert_eq!(ids(&profile.allied_corporations), vec![(20, 1)]);
+        assert_eq!(ids(&profile.allied_alliances), vec![(200, 1)]);
+        assert_eq!(ids(&profile.hostile_corporations), vec![(30, 2), (40, 2)]);
+        assert_eq!(ids(&profile.hostile_alliances), vec![(400, 2)]);
+        assert_eq!(profile.hostile_alliances[0].percent, 66.7);
+    }
+
+    #[test]
+    fn test_alliance_profile() {
+        let killmails: Vec<_> = killmails().into_iter().filter(|killmail| killmail.killmail_id == 2).collect();
+        let mut profile = GroupProfile::build(Entity::Alliance(200), &killmails, now(), 60, 5);
+
+        assert_eq!(profile.alliance, None);
+        assert_eq!(profile.active_members, 1);
+        assert_eq!(profile.allied_alliances[0].entity.id, 100);
+        assert_eq!(profile.hostile_corporations[0].entity.id, 30);
+        assert_eq!(profile.ids(), vec![3, 10, 30, 100, 200, 29990, 30002187]);
+
+        let names: HashMap<_, _> = vec![(200, String::from("Test Alliance"))].into_iter().collect();
+        profile.set_names(&names);
+        assert_eq!(profile.title(), "Test Alliance");
+        let text = profile.to_string();
+        assert!(text.contains("Alliance:              Test Alliance\n"));
+        assert!(text.contains("2022-01-10                 1      0\n"));
+        let json = serde_json::to_value(&profile).unwrap();
+        assert_eq!(json["kind"], "alliance");
+        assert!(json.get("alliance").is_none());
+    }
+
+    #[test]
+    fn test_opponents() {
+        let mut store = store();
+        let now = Utc.ymd(2021, 12, 20).and_hms(0, 0, 0);
+        let ids = |shares: &[Share]| shares.iter().map(|share| (share.entity.id, share.count)).collect::<Vec<_>>();
+
+        let opponents = Opponents::query(&mut store, Entity::Alliance(99010832), now, 30, 5).unwrap();
+        assert_eq!((opponents.kills, opponents.losses), (1, 0));
+        assert_eq!(ids(&opponents.victim_characters), vec![(308241937, 1)]);
+        assert_eq!(ids(&opponents.victim_corporations), vec![(98052179, 1)]);
+        assert_eq!(opponents.victim_alliances[0].percent, 100.0);
+        assert!(opponents.attacker_ships.is_empty());
+
+        let mut opponents = Opponents::query(&mut store, Entity::Alliance(933731581), now, 30, 3).unwrap();
+        assert_eq!((opponents.kills, opponents.losses), (0, 1));
+        assert!(opponents.victim_characters.is_empty());
+        assert_eq!(ids(&opponents.attacker_alliances), vec![(99005824, 1), (99010832, 1)]);
+        assert_eq!(ids(&opponents.attacker_ships), vec![(2212, 1), (17728, 1), (17920, 1)]);
+        assert_eq!(opponents.ids().len(), 12);
+
+        let names: HashMap<_, _> = vec![(99010832, String::from("Test Alliance"))].into_iter().collect();
+        opponents.set_names(&names);
+        let text = opponents.to_string();
+        assert!(text.starts_with("Alliance:              933731581\nLast 30 days\n"));
+        assert!(text.contains("Killed Pilots:         -\n"));
+        assert!(text.contains("Killer Alliances:      99005824 (100%), Test Alliance (100%)\n"));
+        let json = serde_json::to_value(&opponents).unwrap();
+        assert_eq!(json["attacker_alliances"][1]["name"], "Test Alliance");
+
+        let opponents = Opponents::query(&mut store, Entity::Alliance(933731581), now, 1, 3).unwrap();
+        assert_eq!((opponents.losses, opponents.attacker_ships.len()), (0, 0));
+    }
+}
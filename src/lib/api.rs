@@ -0,0 +1,532 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::analytics::{since, CharacterProfile, GroupProfile, Named, Report, PERIODS};
+use crate::heatmap::Heatmap;
+use crate::series::{Bucket, TimeSeries};
+use crate::storage::{open_killmail_reader, Entity, KillmailReader, Level};
+
+type Job = Box<dyn FnOnce(&mut dyn KillmailReader) + Send>;
+
+/// The killmail reader on its own thread, so the sync database clients never block the runtime
+#[derive(Clone)]
+pub struct Database {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+impl Database {
+    pub fn start(mut reader: Box<dyn KillmailReader>) -> Self {
+        let (jobs, mut rx) = mpsc::unbounded_channel::<Job>();
+        std::thread::spawn(move || {
+            while let Some(job) = rx.blocking_recv() {
+                job(reader.as_mut());
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Opens the reader of the database file or postgres:// URL and starts its thread.
+    /// The sync postgres client must be opened outside of the runtime.
+    pub fn open(url: &str) -> anyhow::Result<Self> {
+        Ok(Self::start(open_killmail_reader(url)?))
+    }
+
+    /// Runs the query on the database thread
+    pub async fn run<T, F>(&self, query: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn KillmailReader) -> anyhow::Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |reader| {
+                let _ = tx.send(query(reader));
+            }))
+            .map_err(|_| anyhow!("The database thread has stopped"))?;
+        rx.await.map_err(|_| anyhow!("The database thread has stopped"))?
+    }
+}
+
+/// An error answered with the status and the message as JSON
+#[derive(Debug)]
+pub struct ApiError {
+    pub(crate) status: StatusCode,
+    pub(crate) message: String,
+}
+impl ApiError {
+    pub(crate) fn new<S: Into<String>>(status: StatusCode, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        println!("Failed to answer the request: {}", e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
+    }
+}
+
+/// The query string of the request
+pub struct Query(HashMap<String, String>);
+impl Query {
+    pub fn parse(query: Option<&str>) -> Self {
+        let pairs = query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) => (decode(name), decode(value)),
+                None => (decode(pair), String::new()),
+            });
+        Self(pairs.collect())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// The value of the parameter within `min..=max`, the default if it is missing
+    pub fn number<T>(&self, name: &str, default: T, min: T, max: T) -> Result<T, ApiError>
+    where
+        T: FromStr + PartialOrd + std::fmt::Display,
+    {
+        let value = match self.0.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid {} '{}'", name, value)))?,
+            None => default,
+        };
+        if value < min || value > max {
+            let message = format!("The {} has to be within {}..={}", name, min, max);
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+        }
+        Ok(value)
+    }
+
+    /// The value of the parameter, the default if it is missing
+    pub fn parse_or<T: FromStr>(&self, name: &str, default: T) -> Result<T, ApiError> {
+        match self.0.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid {} '{}'", name, value))),
+            None => Ok(default),
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        matches!(self.get(name), Some("") | Some("1") | Some("true"))
+    }
+}
+
+/// Decodes the `+` and the `%XX` escapes of a form value, the broken escapes are kept as is
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A page of a list and the link to the next one, if any
+#[derive(Serialize)]
+struct Page<T> {
+    page: usize,
+    limit: usize,
+    items: Vec<T>,
+    next: Option<String>,
+}
+
+/// An entry of a top list
+#[derive(Serialize)]
+struct Top {
+    #[serde(flatten)]
+    entity: Named,
+    kills: usize,
+}
+
+/// The body of a successful answer and the number of seconds it may be cached
+pub enum Reply {
+    Json(serde_json::Value, u32),
+    Svg(String, u32),
+}
+
+/// The read-only JSON API over the killmail database
+pub struct Api {
+    database: Database,
+    max_age: u32,
+}
+impl Api {
+    /// The killmails change rarely once they are stored
+    const KILLMAIL_MAX_AGE: u32 = 3600;
+    const MAX_DAYS: i64 = 3650;
+    const MAX_LIMIT: usize = 200;
+    const MAX_PAGE: usize = 100_000;
+
+    /// `max_age` is the number of seconds the lists, profiles and series may be cached
+    pub fn new(database: Database, max_age: u32) -> Self {
+        Self { database, max_age }
+    }
+
+    /// Answers the request, GET and HEAD only
+    pub async fn handle(&self, request: Request<Body>, now: DateTime<Utc>) -> Response<Body> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return error(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "Only GET and HEAD are supported"));
+        }
+        let query = Query::parse(request.uri().query());
+        let reply = match self.route(request.uri().path(), &query, now).await {
+            Ok(reply) => reply,
+            Err(e) => return error(e),
+        };
+        let (content_type, body, max_age) = match reply {
+            Reply::Json(value, max_age) => ("application/json", value.to_string(), max_age),
+            Reply::Svg(svg, max_age) => ("image/svg+xml", svg, max_age),
+        };
+
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        let builder = Response::builder()
+            .header(header::ETAG, &etag)
+            .header(header::CACHE_CONTROL, format!("public, max-age={}", max_age));
+        let not_modified = request
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+        let response = if not_modified {
+            builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+        } else if request.method() == Method::HEAD {
+            builder
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(Body::empty())
+        } else {
+            builder.header(header::CONTENT_TYPE, content_type).body(Body::from(body))
+        };
+        response.unwrap_or_else(|e| error(anyhow!(e).into()))
+    }
+
+    /// Answers the request of the path:
+    /// `/killmail/{id}`, `/{level}/{id}/profile`, `/{level}/{id}/killmails`, `/{level}/{id}/series`,
+    /// `/{level}/{id}/heatmap` and `/top/{level}`
+    pub async fn route(&self, path: &str, query: &Query, now: DateTime<Utc>) -> Result<Reply, ApiError> {
+        let segments: Vec<_> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match segments.as_slice() {
+            ["killmail", id] => self.killmail(parse_id(id)?).await,
+            ["top", level] => self.top(parse_level(level)?, query, now).await,
+            [level, id, "profile"] => self.profile(parse_level(level)?.entity(parse_id(id)?), query, now).await,
+            [level, id, "killmails"] => self.killmails(parse_level(level)?.entity(parse_id(id)?), path, query).await,
+            [level, id, "series"] => self.series(parse_level(level)?.entity(parse_id(id)?), query, now).await,
+            [level, id, "heatmap"] => self.heatmap(parse_level(level)?.entity(parse_id(id)?), query, now).await,
+            _ => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Unknown path {}", path))),
+        }
+    }
+
+    async fn killmail(&self, killmail_id: i32) -> Result<Reply, ApiError> {
+        match self.database.run(move |reader| reader.killmail(killmail_id)).await? {
+            Some(killmail) => Ok(Reply::Json(json(&killmail)?, Self::KILLMAIL_MAX_AGE)),
+            None => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Killmail {} not found", killmail_id))),
+        }
+    }
+
+    /// The killmails of the entity, newest first, `?page=1&limit=50`
+    async fn killmails(&self, entity: Entity, path: &str, query: &Query) -> Result<Reply, ApiError> {
+        let page = query.number("page", 1, 1, Self::MAX_PAGE)?;
+        let limit = query.number("limit", 50, 1, Self::MAX_LIMIT)?;
+        let offset = (page - 1) * limit;
+        // One more to know if there is a next page
+        let mut items = self
+            .database
+            .run(move |reader| reader.latest_killmails_of(entity, offset, limit + 1))
+            .await?;
+        let next = if items.len() > limit {
+            items.truncate(limit);
+            Some(format!("{}?page={}&limit={}", path, page + 1, limit))
+        } else {
+            None
+        };
+        let page = Page {
+            page,
+            limit,
+            items,
+            next,
+        };
+        Ok(Reply::Json(json(&page)?, self.max_age))
+    }
+
+    /// The profile of the entity, `?days=60&top=5&names=true`
+    async fn profile(&self, entity: Entity, query: &Query, now: DateTime<Utc>) -> Result<Reply, ApiError> {
+        let days = query.number("days", 60, 1, Self::MAX_DAYS)?;
+        let top = query.number("top", 5, 1, Self::MAX_LIMIT)?;
+        let longest = PERIODS.iter().copied().chain(Some(days)).max().unwrap_or(days);
+        let killmails = self
+            .database
+            .run(move |reader| reader.killmails_of(entity, &since(now, longest)))
+            .await?;
+        let names = query.flag("names");
+        match entity {
+            Entity::Character(id) => {
+                let profile = CharacterProfile::build(id, &killmails, now, days, top);
+                Ok(Reply::Json(json(&with_names(profile, names).await)?, self.max_age))
+            }
+            _ => {
+                let profile = GroupProfile::build(entity, &killmails, now, days, top);
+                Ok(Reply::Json(json(&with_names(profile, names).await)?, self.max_age))
+            }
+        }
+    }
+
+    /// The kills and losses of the entity, `?days=60&bucket=day&format=json|svg`
+    async fn series(&self, entity: Entity, query: &Query, now: DateTime<Utc>) -> Result<Reply, ApiError> {
+        let days = query.number("days", 60, 1, Self::MAX_DAYS)?;
+        let bucket: Bucket = query.parse_or("bucket", Bucket::Day)?;
+        // Up to a year of hours
+        if bucket == Bucket::Hour && days > 366 {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "The hour bucket is limited to 366 days"));
+        }
+        let series = self
+            .database
+            .run(move |reader| TimeSeries::query(reader, entity, now, days, bucket))
+            .await?;
+        match query.get("format") {
+            None | Some("json") => Ok(Reply::Json(json(&series)?, self.max_age)),
+            Some("svg") => {
+                let title = format!("{} {}: kills and losses by {}", entity.kind(), entity.id(), bucket);
+                Ok(Reply::Svg(series.svg(&title), self.max_age))
+            }
+            Some(format) => Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Unknown format '{}'", format))),
+        }
+    }
+
+    /// The killmails of the entity by weekday and hour with the estimated timezone, `?days=60&format=json|svg`
+    async fn heatmap(&self, entity: Entity, query: &Query, now: DateTime<Utc>) -> Result<Reply, ApiError> {
+        let days = query.number("days", 60, 1, Self::MAX_DAYS)?;
+        let heatmap = self
+            .database
+            .run(move |reader| Heatmap::query(reader, entity, now, days))
+            .await?;
+        match query.get("format") {
+            None | Some("json") => Ok(Reply::Json(json(&heatmap)?, self.max_age)),
+            Some("svg") => {
+                let title = format!("{} {}: activity by weekday and hour (UTC)", entity.kind(), entity.id());
+                Ok(Reply::Svg(heatmap.svg(&title), self.max_age))
+            }
+            Some(format) => Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Unknown format '{}'", format))),
+        }
+    }
+
+    /// The characters, corporations or alliances with the most kills, `?days=7&limit=10&names=true`
+    async fn top(&self, level: Level, query: &Query, now: DateTime<Utc>) -> Result<Reply, ApiError> {
+        let days = query.number("days", 7, 1, Self::MAX_DAYS)?;
+        let limit = query.number("limit", 10, 1, Self::MAX_LIMIT)?;
+        let since = since(now, days);
+        let top = self
+            .database
+            .run(move |reader| reader.top_attackers(level, &since, limit))
+            .await?;
+        let names = if query.flag("names") {
+            let ids: Vec<_> = top.iter().map(|(id, _)| *id).collect();
+            resolve(&ids).await
+        } else {
+            HashMap::new()
+        };
+        let top: Vec<_> = top
+            .into_iter()
+            .map(|(id, kills)| Top {
+                entity: Named {
+                    id,
+                    name: names.get(&id).cloned(),
+                },
+                kills,
+            })
+            .collect();
+        Ok(Reply::Json(json(&top)?, self.max_age))
+    }
+}
+
+fn parse_id(id: &str) -> Result<i32, ApiError> {
+    id.parse()
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid id '{}'", id)))
+}
+
+fn parse_level(level: &str) -> Result<Level, ApiError> {
+    level.parse().map_err(|e: anyhow::Error| ApiError::new(StatusCode::NOT_FOUND, e.to_string()))
+}
+
+fn json<T: Serialize>(value: &T) -> Result<serde_json::Value, ApiError> {
+    Ok(serde_json::to_value(value).map_err(anyhow::Error::from)?)
+}
+
+/// The names of the ids, none if ESI fails, the answer is still useful with the ids
+async fn resolve(ids: &[i32]) -> HashMap<i32, String> {
+    match crate::esi::names(ids).await {
+        Ok(names) => names,
+        Err(e) => {
+            println!("Failed to resolve the names: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+async fn with_names<R: Report>(mut report: R, names: bool) -> R {
+    if names {
+        report.set_names(&resolve(&report.ids()).await);
+    }
+    report
+}
+
+pub(crate) fn error(e: ApiError) -> Response<Body> {
+    let body = serde_json::json!({ "error": e.message }).to_string();
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = e.status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{fixtures, KillmailStore, Source};
+    use chrono::TimeZone;
+
+    fn api() -> Api {
+        Api::new(Database::start(Box::new(fixtures::store())), 60)
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 12, 20).and_hms(0, 0, 0)
+    }
+
+    async fn get(api: &Api, uri: &str) -> (StatusCode, hyper::HeaderMap, serde_json::Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = api.handle(request, now()).await;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, headers, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[test]
+    fn test_query() {
+        let query = Query::parse(Some("q=Seb+Odessa%21&names&page=2&broken=%zz"));
+        assert_eq!(query.get("q"), Some("Seb Odessa!"));
+        assert!(query.flag("names"));
+        assert_eq!(query.number("page", 1, 1, 10).unwrap(), 2);
+        assert_eq!(query.get("broken"), Some("%zz"));
+        assert!(query.number("page", 1, 3, 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_killmail_and_caching() {
+        let api = api();
+        let (status, headers, body) = get(&api, "/killmail/97318112").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["killmail_id"], 97318112);
+        assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=3600");
+
+        let etag = headers[header::ETAG].clone();
+        let request = Request::get("/killmail/97318112")
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(api.handle(request, now()).await.status(), StatusCode::NOT_MODIFIED);
+
+        assert_eq!(get(&api, "/killmail/1").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&api, "/killmail/abc").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(get(&api, "/planet/1/profile").await.0, StatusCode::NOT_FOUND);
+        let request = Request::post("/killmail/97318112").body(Body::empty()).unwrap();
+        assert_eq!(api.handle(request, now()).await.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_pages() {
+        let api = api();
+        let (status, headers, body) = get(&api, "/corporation/98676166/killmails?limit=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=60");
+        assert_eq!(body["items"][0]["killmail_id"], 97318112);
+        assert!(body["next"].is_null());
+
+        let (_, _, body) = get(&api, "/corporation/98676166/killmails?page=2&limit=1").await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 0);
+        let (status, _, body) = get(&api, "/corporation/98676166/killmails?limit=1000").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "The limit has to be within 1..=200");
+        let (status, _, body) = get(&api, "/corporation/98676166/killmails?page=100001").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "The page has to be within 1..=100000");
+    }
+
+    #[tokio::test]
+    async fn test_next_page() {
+        let mut store = fixtures::store();
+        let mut newer = fixtures::killmail();
+        newer.killmail_id += 1;
+        newer.killmail_time = String::from("2021-12-13T10:00:00Z");
+        store.insert_killmails(vec![newer], Source::Esi).unwrap();
+        let api = Api::new(Database::start(Box::new(store)), 60);
+
+        let (_, _, body) = get(&api, "/corporation/98676166/killmails?limit=1").await;
+        assert_eq!(body["items"][0]["killmail_id"], 97318113);
+        assert_eq!(body["next"], "/corporation/98676166/killmails?page=2&limit=1");
+
+        let (status, _, body) = get(&api, body["next"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["page"], 2);
+        assert_eq!(body["items"][0]["killmail_id"], 97318112);
+        assert!(body["next"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_profile_series_and_top() {
+        let api = api();
+        let (status, _, body) = get(&api, "/alliance/933731581/profile?days=30").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["kind"], "alliance");
+        assert_eq!(body["activity"][0]["losses"], 1);
+
+        let (_, _, body) = get(&api, "/character/2112698901/series?days=30&bucket=week").await;
+        assert_eq!(body["bucket"], "week");
+        assert_eq!(body["points"].as_array().unwrap().iter().map(|p| p["kills"].as_u64().unwrap()).sum::<u64>(), 1);
+        let request = Request::get("/character/2112698901/series?format=svg").body(Body::empty()).unwrap();
+        let response = api.handle(request, now()).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+        assert_eq!(get(&api, "/character/2112698901/series?bucket=month").await.0, StatusCode::BAD_REQUEST);
+
+        let (_, _, body) = get(&api, "/alliance/933731581/heatmap?days=30").await;
+        assert_eq!(body["matrix"][6][15], 1);
+        assert_eq!(body["timezone"]["prime_start"], 12);
+        let request = Request::get("/alliance/933731581/heatmap?format=svg").body(Body::empty()).unwrap();
+        let response = api.handle(request, now()).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+        assert_eq!(get(&api, "/alliance/933731581/heatmap?format=png").await.0, StatusCode::BAD_REQUEST);
+
+        let (_, _, body) = get(&api, "/top/corporation?days=30&limit=2").await;
+        assert_eq!(body, serde_json::json!([{"id": 1000162, "kills": 1}, {"id": 98340855, "kills": 1}]));
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::anyhow;
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::Killmail;
+
+/// Opens a `.tar`, `.tar.gz`/`.tgz` or `.tar.bz2` archive by the file extension
+pub fn open_archive(path: &Path) -> anyhow::Result<tar::Archive<Box<dyn Read>>> {
+    let name = path.to_string_lossy();
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = if name.ends_with(".tar.bz2") || name.ends_with(".tbz2") {
+        Box::new(BzDecoder::new(file))
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Box::new(GzDecoder::new(file))
+    } else if name.ends_with(".tar") {
+        Box::new(file)
+    } else {
+        return Err(anyhow!("{} is not a tar archive", name));
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Streams the ESI killmails `*.json` of a bulk archive, such as the daily EVE Ref dumps,
+/// to `handle`. The `*.json` entries that are not killmails are skipped and returned with the reason.
+pub fn read_killmail_archive<F>(path: &Path, mut handle: F) -> anyhow::Result<Vec<String>>
+where
+    F: FnMut(Killmail) -> anyhow::Result<()>,
+{
+    let mut skipped = Vec::new();
+    let mut archive = open_archive(path)?;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let name = entry.path()?.into_owned();
+        if !entry.header().entry_type().is_file() || name.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match serde_json::from_reader::<_, Killmail>(entry) {
+            Ok(killmail) => handle(killmail)?,
+            Err(e) => skipped.push(format!("{}: {}", name.display(), e)),
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_killmail_archive() {
+        let mut killmails = Vec::new();
+        let skipped = read_killmail_archive(Path::new("doc/killmails-2022-01-17.tar.bz2"), |killmail| {
+            killmails.push(killmail);
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(skipped.is_empty());
+        assert_eq!(killmails.len(), 2);
+        assert_eq!(killmails[0].killmail_id, 97318112);
+        assert_eq!(killmails[0].attackers.len(), 7);
+        assert_eq!(killmails[1].killmail_id, 98190688);
+        assert!(killmails[1].zkb.is_some());
+    }
+
+    #[test]
+    fn test_open_archive_rejects_unknown_extension() {
+        assert!(open_archive(Path::new("doc/killmail.json")).is_err());
+    }
+}
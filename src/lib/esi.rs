@@ -0,0 +1,55 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::storage::Entity;
+
+const NAMES_URL: &str = "https://esi.evetech.net/latest/universe/names/";
+const IDS_URL: &str = "https://esi.evetech.net/latest/universe/ids/";
+/// ESI resolves at most this number of ids per request
+const NAMES_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct Name {
+    id: i32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Ids {
+    #[serde(default)]
+    characters: Vec<Name>,
+    #[serde(default)]
+    corporations: Vec<Name>,
+    #[serde(default)]
+    alliances: Vec<Name>,
+}
+
+/// Resolves the names of characters, corporations, alliances, types and solar systems.
+/// ESI rejects the whole request if any id is unknown.
+pub async fn names(ids: &[i32]) -> anyhow::Result<HashMap<i32, String>> {
+    let client = reqwest::Client::new();
+    let mut names = HashMap::new();
+    for chunk in ids.chunks(NAMES_LIMIT) {
+        let response = client.post(NAMES_URL).json(chunk).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("{} for {}", response.status(), NAMES_URL));
+        }
+        let chunk: Vec<Name> = response.json().await?;
+        names.extend(chunk.into_iter().map(|name| (name.id, name.name)));
+    }
+    Ok(names)
+}
+
+/// The characters, corporations and alliances with exactly this name
+pub async fn search(name: &str) -> anyhow::Result<Vec<(Entity, String)>> {
+    let response = reqwest::Client::new().post(IDS_URL).json(&[name]).send().await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} for {}", response.status(), IDS_URL));
+    }
+    let ids: Ids = response.json().await?;
+    let characters = ids.characters.into_iter().map(|name| (Entity::Character(name.id), name.name));
+    let corporations = ids.corporations.into_iter().map(|name| (Entity::Corporation(name.id), name.name));
+    let alliances = ids.alliances.into_iter().map(|name| (Entity::Alliance(name.id), name.name));
+    Ok(characters.chain(corporations).chain(alliances).collect())
+}
@@ -0,0 +1,306 @@
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::analytics::{since, Named, Report};
+use crate::series::escape;
+use crate::storage::{Entity, KillmailReader};
+
+/// The short names of the rows, the weeks start on Monday
+pub const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// The number of the busiest consecutive hours the timezone is estimated from
+const PRIME_HOURS: usize = 6;
+
+/// The local hour in the middle of the prime hours of the pilots
+const PRIME_CENTER: i32 = 21;
+
+/// The timezone the prime hours of an entity fit best
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct Timezone {
+    /// USTZ, EUTZ or AUTZ
+    pub name: &'static str,
+    pub utc_offset: i32,
+    /// The first and the one after the last of the prime hours, in UTC
+    pub prime_start: u32,
+    pub prime_end: u32,
+    /// The part of the killmails in the prime hours
+    pub percent: f64,
+}
+impl Timezone {
+    /// The timezone of the busiest `PRIME_HOURS` of the day. On a tie the window whose killmails
+    /// are the closest to its middle wins, so sparse activity is not pushed to the edge of the window.
+    fn estimate(hours: &[usize; 24]) -> Option<Self> {
+        let total: usize = hours.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let window = |start: usize| -> usize { (start..start + PRIME_HOURS).map(|hour| hours[hour % 24]).sum() };
+        // The distance of the weighted mean hour from the middle of the window, in half hours
+        let skew = |start: usize| -> i64 {
+            let moment = |index: usize| (2 * index as i64 + 1 - PRIME_HOURS as i64) * hours[(start + index) % 24] as i64;
+            (0..PRIME_HOURS).map(moment).sum::<i64>().abs()
+        };
+        let start = (0..24).min_by_key(|&start| (std::cmp::Reverse(window(start)), skew(start), start)).unwrap_or(0);
+        let utc_offset = (PRIME_CENTER - (start + PRIME_HOURS / 2) as i32 + 11).rem_euclid(24) - 11;
+        let name = match utc_offset {
+            -11..=-3 => "USTZ",
+            -2..=4 => "EUTZ",
+            _ => "AUTZ",
+        };
+        Some(Self {
+            name,
+            utc_offset,
+            prime_start: start as u32,
+            prime_end: ((start + PRIME_HOURS) % 24) as u32,
+            percent: (window(start) as f64 * 1000.0 / total as f64).round() / 10.0,
+        })
+    }
+}
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (UTC{:+}), {:02}:00-{:02}:00 UTC with {:.0}% of the killmails",
+            self.name, self.utc_offset, self.prime_start, self.prime_end, self.percent
+        )
+    }
+}
+
+/// The killmails of an entity by the weekday and the hour of the day in UTC
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct Heatmap {
+    pub kind: &'static str,
+    pub entity: Named,
+    pub days: i64,
+    pub killmails: usize,
+    /// The rows of `WEEKDAYS` with the killmails of every hour
+    pub matrix: [[usize; 24]; 7],
+    /// The killmails of every hour over the week
+    pub hours: [usize; 24],
+    pub timezone: Option<Timezone>,
+}
+impl Heatmap {
+    /// Counts the killmail times returned by `KillmailReader::killmail_times`, both the kills and the losses
+    pub fn build(entity: Entity, times: &[(String, bool)], days: i64) -> Self {
+        let mut matrix = [[0; 24]; 7];
+        for (time, _) in times {
+            if let Ok(time) = Utc.datetime_from_str(time, "%Y-%m-%dT%H:%M:%SZ") {
+                matrix[time.weekday().num_days_from_monday() as usize][time.hour() as usize] += 1;
+            }
+        }
+        let mut hours = [0; 24];
+        for row in &matrix {
+            for (hour, count) in row.iter().enumerate() {
+                hours[hour] += count;
+            }
+        }
+        Self {
+            kind: entity.kind(),
+            entity: Named::new(entity.id()),
+            days,
+            killmails: hours.iter().sum(),
+            matrix,
+            hours,
+            timezone: Timezone::estimate(&hours),
+        }
+    }
+
+    /// Queries the killmail times of the entity and builds the heatmap of the last `days`
+    pub fn query(
+        reader: &mut dyn KillmailReader,
+        entity: Entity,
+        now: DateTime<Utc>,
+        days: i64,
+    ) -> anyhow::Result<Self> {
+        let times = reader.killmail_times(entity, &since(now, days))?;
+        Ok(Self::build(entity, &times, days))
+    }
+
+    /// A grid of the weekdays and the hours, the busier the darker
+    pub fn svg(&self, title: &str) -> String {
+        const CELL: f64 = 28.0;
+        const ROW: f64 = 22.0;
+        const LEFT: f64 = 40.0;
+        const TOP: f64 = 44.0;
+        let max = self.matrix.iter().flat_map(|row| row.iter()).copied().max().unwrap_or(0).max(1);
+        let width = LEFT + 24.0 * CELL + 8.0;
+        let height = TOP + 7.0 * ROW + 28.0;
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="11">"#,
+            w = width,
+            h = height
+        );
+        svg.push_str(&format!(r#"<text x="{}" y="16" font-size="13">{}</text>"#, LEFT, escape(title)));
+        for hour in 0..24 {
+            svg.push_str(&format!(
+                r#"<text x="{:.1}" y="{}" text-anchor="middle">{:02}</text>"#,
+                LEFT + (hour as f64 + 0.5) * CELL,
+                TOP - 6.0,
+                hour
+            ));
+        }
+        for (day, row) in self.matrix.iter().enumerate() {
+            let y = TOP + day as f64 * ROW;
+            svg.push_str(&format!(r#"<text x="4" y="{:.1}">{}</text>"#, y + ROW - 7.0, WEEKDAYS[day]));
+            for (hour, &count) in row.iter().enumerate() {
+                let fill = if count == 0 {
+                    String::from(r##"fill="#eeeeee""##)
+                } else {
+                    format!(r##"fill="#c62828" fill-opacity="{:.2}""##, 0.15 + 0.85 * count as f64 / max as f64)
+                };
+                svg.push_str(&format!(
+                    r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" {} stroke="white"><title>{} {:02}:00: {} killmails</title></rect>"#,
+                    LEFT + hour as f64 * CELL,
+                    y,
+                    CELL,
+                    ROW,
+                    fill,
+                    WEEKDAYS[day],
+                    hour,
+                    count
+                ));
+            }
+        }
+        let timezone = match self.timezone {
+            Some(ref timezone) => format!("Estimated timezone: {}", timezone),
+            None => String::from("No killmails"),
+        };
+        svg.push_str(&format!(r#"<text x="{}" y="{}">{}</text>"#, LEFT, height - 8.0, escape(&timezone)));
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+impl Report for Heatmap {
+    fn title(&self) -> String {
+        self.entity.to_string()
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        vec![self.entity.id]
+    }
+
+    fn set_names(&mut self, names: &HashMap<i32, String>) {
+        self.entity.name = names.get(&self.entity.id).cloned();
+    }
+}
+impl fmt::Display for Heatmap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut kind = self.kind.to_owned();
+        kind[..1].make_ascii_uppercase();
+        writeln!(f, "{:<23}{}", format!("{}:", kind), self.entity)?;
+        writeln!(f, "Last {} days, {} killmails by weekday and hour (UTC)", self.days, self.killmails)?;
+        write!(f, "{:<5}", "")?;
+        for hour in 0..24 {
+            write!(f, "{:>3}", format!("{:02}", hour))?;
+        }
+        writeln!(f)?;
+        let write_row = |f: &mut fmt::Formatter, label: &str, row: &[usize; 24]| -> fmt::Result {
+            write!(f, "{:<5}", label)?;
+            for &count in row {
+                if count == 0 {
+                    write!(f, "{:>3}", ".")?;
+                } else {
+                    write!(f, "{:>3}", count)?;
+                }
+            }
+            writeln!(f)
+        };
+        for (day, row) in self.matrix.iter().enumerate() {
+            write_row(f, WEEKDAYS[day], row)?;
+        }
+        write_row(f, "All", &self.hours)?;
+        match self.timezone {
+            Some(ref timezone) => writeln!(f, "{:<23}{}", "Timezone:", timezone),
+            None => writeln!(f, "{:<23}-", "Timezone:"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(hours: &[(&str, usize)]) -> Vec<(String, bool)> {
+        hours
+            .iter()
+            .flat_map(|(time, count)| (0..*count).map(move |_| (String::from(*time), false)))
+            .collect()
+    }
+
+    #[test]
+    fn test_build() {
+        // Monday and Saturday evenings in Europe, one early morning and a broken time
+        let times = times(&[
+            ("2022-01-10T19:10:00Z", 3),
+            ("2022-01-15T20:30:00Z", 2),
+            ("2022-01-15T21:00:00Z", 1),
+            ("2022-01-20T04:00:00Z", 1),
+            ("broken", 1),
+        ]);
+        let heatmap = Heatmap::build(Entity::Corporation(10), &times, 30);
+        assert_eq!(heatmap.killmails, 7);
+        assert_eq!(heatmap.matrix[0][19], 3);
+        assert_eq!(heatmap.matrix[5][20], 2);
+        assert_eq!(heatmap.matrix[3][4], 1);
+        assert_eq!(heatmap.hours[19..22], [3, 2, 1]);
+
+        let timezone = heatmap.timezone.unwrap();
+        // The evening activity from 19:00 to 22:00 is in the middle of the prime hours
+        assert_eq!((timezone.prime_start, timezone.prime_end), (17, 23));
+        assert_eq!((timezone.name, timezone.utc_offset), ("EUTZ", 1));
+        assert_eq!(timezone.percent, 85.7);
+        assert!(Heatmap::build(Entity::Corporation(10), &[], 30).timezone.is_none());
+    }
+
+    #[test]
+    fn test_timezone() {
+        let estimate = |start: usize| {
+            let mut hours = [0; 24];
+            for hour in start..start + PRIME_HOURS {
+                hours[hour % 24] = 5;
+            }
+            let timezone = Timezone::estimate(&hours).unwrap();
+            (timezone.name, timezone.utc_offset, timezone.prime_start)
+        };
+        assert_eq!(estimate(17), ("EUTZ", 1, 17));
+        // Over midnight in UTC
+        assert_eq!(estimate(23), ("USTZ", -5, 23));
+        assert_eq!(estimate(8), ("AUTZ", 10, 8));
+        assert_eq!(estimate(6), ("AUTZ", 12, 6));
+        assert_eq!(estimate(5), ("USTZ", -11, 5));
+    }
+
+    #[test]
+    fn test_svg_and_text() {
+        let times = times(&[("2022-01-10T19:10:00Z", 2), ("2022-01-16T00:30:00Z", 1)]);
+        let mut heatmap = Heatmap::build(Entity::Alliance(100), &times, 30);
+        let svg = heatmap.svg("Activity of <SO>");
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains("Activity of &lt;SO&gt;"));
+        assert_eq!(svg.matches("<rect ").count(), 7 * 24);
+        let busiest = r##"fill="#c62828" fill-opacity="1.00" stroke="white"><title>Mon 19:00: 2 killmails</title>"##;
+        assert!(svg.contains(busiest));
+        assert!(svg.contains("<title>Sun 00:00: 1 killmails</title>"));
+
+        let names: HashMap<_, _> = vec![(100, String::from("Test Alliance"))].into_iter().collect();
+        heatmap.set_names(&names);
+        assert_eq!(heatmap.title(), "Test Alliance");
+        let text = heatmap.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], "Alliance:              Test Alliance");
+        assert_eq!(lines[1], "Last 30 days, 3 killmails by weekday and hour (UTC)");
+        assert!(lines[2].starts_with("      00 01 02"));
+        assert_eq!(lines[3], format!("Mon  {}  2  .  .  .  .", "  .".repeat(19)));
+        assert!(lines[9].starts_with("Sun    1  ."));
+        assert!(lines[10].starts_with("All    1  ."));
+        assert_eq!(lines[11], "Timezone:              EUTZ (UTC-1), 19:00-01:00 UTC with 100% of the killmails");
+
+        let json = serde_json::to_value(&heatmap).unwrap();
+        assert_eq!(json["matrix"][0][19], 2);
+        assert_eq!(json["timezone"]["name"], "EUTZ");
+        assert_eq!(json["entity"]["name"], "Test Alliance");
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::anyhow;
+use hyper::body::Buf;
+use hyper::Client;
+use hyper_tls::HttpsConnector;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use time::{format_description, Date, Duration, OffsetDateTime};
+
+use crate::archive::open_archive;
+use crate::{DailyReport, IdHashBinary};
+
+/// The days of the zKillboard history already sent to the hash manager.
+/// Every line of the file is `YYYY-MM-DD <killmails count>`, the last line of a day wins.
+pub struct HistoryState {
+    path: PathBuf,
+    days: BTreeMap<String, usize>,
+}
+impl HistoryState {
+    pub fn load<P: Into<PathBuf>>(path: P) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut days = BTreeMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                let mut fields = line.split_whitespace();
+                if let (Some(day), Some(count)) = (fields.next(), fields.next()) {
+                    days.insert(day.to_owned(), count.parse()?);
+                }
+            }
+        }
+        Ok(Self { path, days })
+    }
+
+    pub fn contains(&self, day: &str) -> bool {
+        self.days.contains_key(day)
+    }
+
+    pub fn count(&self, day: &str) -> Option<usize> {
+        self.days.get(day).copied()
+    }
+
+    /// Whether the day has to be fetched: it was never saved or
+    /// the zKillboard total of the day differs from the saved count
+    pub fn is_stale(&self, day: &str, total: Option<usize>) -> bool {
+        match (self.count(day), total) {
+            (None, _) => true,
+            (Some(count), Some(total)) => count != total,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// The days from `first` (YYYY-MM-DD) on whose zKillboard totals (by YYYYMMDD)
+    /// differ from the saved counts, the days never saved included, oldest first
+    pub fn changed_days(&self, totals: &HashMap<String, usize>, first: &str) -> Vec<String> {
+        let mut days: Vec<_> = totals
+            .iter()
+            .filter_map(|(date, total)| day_of_date(date).map(|day| (day, *total)))
+            .filter(|(day, total)| day.as_str() >= first && self.is_stale(day, Some(*total)))
+            .map(|(day, _)| day)
+            .collect();
+        days.sort();
+        days
+    }
+
+    /// Records the day as complete and appends it to the file
+    pub fn save(&mut self, day: &str, count: usize) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{} {}", day, count)?;
+        self.days.insert(day.to_owned(), count);
+        Ok(())
+    }
+}
+
+/// Parses `YYYY-MM-DD`, `today`, `yesterday` or `N days ago` relative to `today` (UTC)
+pub fn parse_day(value: &str, today: Date) -> anyhow::Result<Date> {
+    let words: Vec<_> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["today"] => Ok(today),
+        ["yesterday"] => Ok(today - Duration::days(1)),
+        [count, "day", "ago"] | [count, "days", "ago"] => {
+            let count: i64 = count.parse().map_err(|_| anyhow!("Invalid number of days in '{}'", value))?;
+            Ok(today - Duration::days(count))
+        }
+        _ => {
+            let format = format_description::parse("[year]-[month]-[day]")?;
+            Date::parse(value, &format)
+                .map_err(|e| anyhow!("'{}' is not YYYY-MM-DD, today, yesterday or N days ago: {}", value, e))
+        }
+    }
+}
+
+/// The days of the window from `last` to `now` that miss at least `min_gap` of it,
+/// limited to the `max` latest ones. A window shorter than `min_gap` misses no days.
+pub fn missed_days(last: OffsetDateTime, now: OffsetDateTime, min_gap: Duration, max: usize) -> Vec<Date> {
+    let mut days = Vec::new();
+    if now - last < min_gap {
+        return days;
+    }
+    let mut day = now.date();
+    while day >= last.date() && days.len() < max {
+        let midnight = day.midnight().assume_utc();
+        let missed = (midnight + Duration::days(1)).min(now) - midnight.max(last);
+        if missed >= min_gap {
+            days.push(day);
+        }
+        day -= Duration::days(1);
+    }
+    days.reverse();
+    days
+}
+
+/// Converts the name of a zKillboard history file `YYYYMMDD.json` to the day `YYYY-MM-DD`
+pub fn day_of(file_name: &str) -> Option<String> {
+    day_of_date(file_name.strip_suffix(".json")?)
+}
+
+/// Converts the zKillboard date `YYYYMMDD` to the day `YYYY-MM-DD`
+pub fn day_of_date(date: &str) -> Option<String> {
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{}-{}-{}", &date[0..4], &date[4..6], &date[6..8]))
+}
+
+/// Reads the zKillboard history files from a directory or a tar archive
+/// and passes every day with its killmail hashes to `handle`. Other files are skipped.
+pub fn read_history_files<F>(path: &Path, mut handle: F) -> anyhow::Result<()>
+where
+    F: FnMut(String, HashMap<i32, String>) -> anyhow::Result<()>,
+{
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            if let Some(day) = entry.file_name().to_str().and_then(day_of) {
+                let map = serde_json::from_reader(BufReader::new(File::open(entry.path())?))
+                    .map_err(|e| anyhow!("{}: {}", entry.path().display(), e))?;
+                handle(day, map)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut archive = open_archive(path)?;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        if let Some(day) = path.file_name().and_then(|name| name.to_str()).and_then(day_of) {
+            let map = serde_json::from_reader(entry).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            handle(day, map)?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the report of the day (YYYY-MM-DD) from the killmail hashes of the zKillboard history
+pub fn daily_report(day: String, map: HashMap<i32, String>) -> anyhow::Result<DailyReport> {
+    let mut report = DailyReport::new(day);
+    for (id, hash) in &map {
+        let id_hash = IdHashBinary::try_from((id, hash)).map_err(|err| anyhow!(err))?;
+        report.killmails.push(id_hash);
+    }
+    Ok(report)
+}
+
+/// Fetches the killmail hashes of the day (YYYYMMDD), retrying with a growing delay
+pub async fn fetch_day(date: &str, retries: u32) -> anyhow::Result<HashMap<i32, String>> {
+    let url = format!("https://zkillboard.com/api/history/{}.json", date);
+    let mut timeout = std::time::Duration::from_secs(3);
+    let mut attempt = 0;
+    loop {
+        match fetch_json(url.clone()).await {
+            Ok(map) => return Ok(map),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                println!("{} - {}. Retry {}/{} after {} secs", date, e, attempt, retries, timeout.as_secs());
+                tokio::time::sleep(timeout).await;
+                if timeout.as_secs() < 120 {
+                    timeout *= 2;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The number of killmails per day (YYYYMMDD) known to zKillboard
+pub async fn fetch_totals() -> anyhow::Result<HashMap<String, usize>> {
+    fetch_json(String::from("https://zkillboard.com/api/history/totals.json")).await
+}
+
+async fn fetch_json<T: DeserializeOwned>(url: String) -> anyhow::Result<T> {
+    let uri = url.parse()?;
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let result = client.get(uri).await?;
+    if !result.status().is_success() {
+        return Err(anyhow!("{} for {}", result.status(), url));
+    }
+    let body = hyper::body::aggregate(result).await?;
+    let value: T = serde_json::from_reader(body.reader())?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::format_description::well_known::Rfc3339;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("zkb_tools_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn read(path: &Path) -> Vec<(String, usize)> {
+        let mut days = Vec::new();
+        read_history_files(path, |day, map| {
+            days.push((day, map.len()));
+            Ok(())
+        })
+        .unwrap();
+        days.sort();
+        days
+    }
+
+    const DAY_1: &str = r#"{"97318112":"1a38d4921711476e5ea304f799a1552b4d2e5d28"}"#;
+    const DAY_2: &str = r#"{"1":"1a38d4921711476e5ea304f799a1552b4d2e5d28","2":"9377f28e34eabc18162e57e7e85f7a15c9339604"}"#;
+
+    #[test]
+    fn test_parse_day() {
+        let today = Date::from_calendar_date(2022, time::Month::January, 17).unwrap();
+        let day = |value| parse_day(value, today).unwrap().to_string();
+        assert_eq!(day("today"), "2022-01-17");
+        assert_eq!(day("yesterday"), "2022-01-16");
+        assert_eq!(day("1 day ago"), "2022-01-16");
+        assert_eq!(day("30 days ago"), "2021-12-18");
+        assert_eq!(day("2021-10-01"), "2021-10-01");
+        assert!(parse_day("some days ago", today).is_err());
+        assert!(parse_day("20211001", today).is_err());
+    }
+
+    #[test]
+    fn test_missed_days() {
+        let at = |value: &str| OffsetDateTime::parse(value, &Rfc3339).unwrap();
+        let now = at("2022-01-17T12:00:00Z");
+        let gap = Duration::minutes(15);
+        let days = |last, max| {
+            missed_days(at(last), now, gap, max)
+                .iter()
+                .map(|day| day.to_string())
+                .collect::<Vec<_>>()
+        };
+        // A short blip misses nothing
+        assert!(days("2022-01-17T11:59:00Z", 7).is_empty());
+        assert_eq!(days("2022-01-17T11:00:00Z", 7), vec!["2022-01-17"]);
+        assert_eq!(days("2022-01-15T12:00:00Z", 7), vec!["2022-01-15", "2022-01-16", "2022-01-17"]);
+        assert_eq!(days("2021-12-01T00:00:00Z", 2), vec!["2022-01-16", "2022-01-17"]);
+        // Yesterday ended a few minutes after the last killmail, only today is missing
+        assert_eq!(days("2022-01-16T23:55:00Z", 7), vec!["2022-01-17"]);
+        // The outage ends just after midnight, today is not missing
+        let now = at("2022-01-17T00:05:00Z");
+        assert_eq!(missed_days(at("2022-01-16T22:00:00Z"), now, gap, 7).len(), 1);
+        assert!(missed_days(at("2022-01-18T00:00:00Z"), now, gap, 7).is_empty());
+    }
+
+    #[test]
+    fn test_daily_report() {
+        let map = serde_json::from_str(DAY_2).unwrap();
+        let report = daily_report(String::from("2022-01-18"), map).unwrap();
+        assert_eq!(report.date, "2022-01-18");
+        assert_eq!(report.killmails.len(), 2);
+
+        let map = serde_json::from_str(r#"{"1":"1a3"}"#).unwrap();
+        assert!(daily_report(String::from("2022-01-18"), map).is_err());
+    }
+
+    #[test]
+    fn test_day_of() {
+        assert_eq!(day_of("20220117.json"), Some(String::from("2022-01-17")));
+        assert_eq!(day_of("totals.json"), None);
+        assert_eq!(day_of("20220117.json.bak"), None);
+        assert_eq!(day_of_date("20220117"), Some(String::from("2022-01-17")));
+        assert_eq!(day_of_date("2022011"), None);
+        assert_eq!(day_of_date("2022-117"), None);
+    }
+
+    #[test]
+    fn test_read_history_directory() {
+        let dir = temp_path("history");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("20220117.json"), DAY_1).unwrap();
+        std::fs::write(dir.join("20220118.json"), DAY_2).unwrap();
+        std::fs::write(dir.join("totals.json"), r#"{"20220117":1}"#).unwrap();
+
+        let expected = vec![(String::from("2022-01-17"), 1), (String::from("2022-01-18"), 2)];
+        assert_eq!(read(&dir), expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_history_tarball() {
+        let path = temp_path("history.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in [("history/20220117.json", DAY_1), ("history/20220118.json", DAY_2), ("README", "")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let expected = vec![(String::from("2022-01-17"), 1), (String::from("2022-01-18"), 2)];
+        assert_eq!(read(&path), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let state = HistoryState::load(temp_path("missing.state")).unwrap();
+        assert!(!state.contains("2022-01-17"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_path("save.state");
+        let mut state = HistoryState::load(&path).unwrap();
+        state.save("2022-01-17", 10).unwrap();
+        state.save("2022-01-18", 20).unwrap();
+        state.save("2022-01-17", 11).unwrap();
+
+        let state = HistoryState::load(&path).unwrap();
+        assert!(state.contains("2022-01-18"));
+        assert_eq!(state.count("2022-01-17"), Some(11));
+        assert_eq!(state.count("2022-01-19"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_changed_days() {
+        let path = temp_path("changed.state");
+        let mut state = HistoryState::load(&path).unwrap();
+        state.save("2022-01-16", 5).unwrap();
+        state.save("2022-01-17", 10).unwrap();
+        state.save("2022-01-18", 20).unwrap();
+        let totals: HashMap<_, _> = vec![
+            (String::from("20220115"), 3),
+            (String::from("20220116"), 6),
+            (String::from("20220117"), 10),
+            (String::from("20220118"), 21),
+            (String::from("20220119"), 1),
+            (String::from("bogus"), 1),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(state.changed_days(&totals, "2022-01-16"), vec!["2022-01-16", "2022-01-18", "2022-01-19"]);
+        assert_eq!(state.changed_days(&totals, "2022-01-19"), vec!["2022-01-19"]);
+        assert!(state.changed_days(&totals, "2022-01-20").is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let path = temp_path("stale.state");
+        let mut state = HistoryState::load(&path).unwrap();
+        state.save("2022-01-17", 10).unwrap();
+
+        assert!(!state.is_stale("2022-01-17", Some(10)));
+        assert!(!state.is_stale("2022-01-17", None));
+        assert!(state.is_stale("2022-01-17", Some(12)));
+        assert!(state.is_stale("2022-01-18", None));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,13 +1,32 @@
-use hex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 
+pub mod analytics;
+pub mod api;
+pub mod archive;
+pub mod esi;
+pub mod heatmap;
+pub mod history;
+pub mod live;
+pub mod pubacks;
+pub mod redisq;
+pub mod series;
+pub mod server;
+pub mod spool;
+pub mod storage;
+pub mod web;
+
 type Hash = [u8; 20];
 pub type IdHash = (i32, String);
 
-pub const CMD_TOPIC: &'static str = "zkb/commands";
-pub const DATA_TOPIC: &'static str = "zkb/data";
+pub const CMD_TOPIC: &str = "zkb/commands";
+pub const DATA_TOPIC: &str = "zkb/data";
+pub const CHANNEL_TOPIC: &str = "zkb/channels";
+/// The prefix of the per-entity topics of the live killmails, such as `zkb/kills/alliance/<id>`.
+/// The payload is the bincode encoded `Killmail`.
+pub const KILLS_TOPIC: &str = "zkb/kills";
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum CmdEvent {
@@ -19,24 +38,60 @@ pub enum CmdEvent {
     Quit,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum DataEvent {
     HashesToHandle(Vec<IdHash>),
     KillmailToStore(Killmail),
 }
 
+/// Changes the zKillboard websocket channels, such as `killstream` or `alliance:99000001`,
+/// of the running websocket client
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum ChannelEvent {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct DailyReport {
     pub date: String,
     pub killmails: Vec<IdHashBinary>,
+    /// The 1-based number of the chunk of the day
+    pub sequence: u32,
+    /// The number of chunks of the day
+    pub total: u32,
 }
 impl DailyReport {
+    /// The number of killmails in a chunk keeps the message far below the MQTT packet limit
+    pub const CHUNK_SIZE: usize = 10_000;
+
     pub fn new(day: String) -> Self {
         Self {
             date: day,
             killmails: Vec::new(),
+            sequence: 1,
+            total: 1,
         }
     }
+
+    /// Splits the report into chunks of at most `size` killmails, an empty report stays a single chunk
+    pub fn split(self, size: usize) -> Vec<DailyReport> {
+        let chunks: Vec<_> = self.killmails.chunks(size.max(1)).map(|chunk| chunk.to_vec()).collect();
+        if chunks.is_empty() {
+            return vec![self];
+        }
+        let total = chunks.len() as u32;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, killmails)| Self {
+                date: self.date.clone(),
+                killmails,
+                sequence: index as u32 + 1,
+                total,
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -107,6 +162,36 @@ mod tests {
         assert_eq!(value.unwrap_err(), IdHashBinary::ERR_ARRAY);
     }
 
+    #[test]
+    fn test_split_daily_report() {
+        let mut report = DailyReport::new(String::from("2022-01-17"));
+        for id in 0..5 {
+            report.killmails.push(IdHashBinary::try_from((id, "1a38d4921711476e5ea304f799a1552b4d2e5d28")).unwrap());
+        }
+        let chunks = report.clone().split(2);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.iter().map(|c| c.killmails.len()).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert_eq!(chunks.iter().map(|c| c.sequence).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(chunks.iter().all(|c| c.total == 3 && c.date == report.date));
+        assert_eq!(chunks[2].killmails[0].get_id(), 4);
+    }
+
+    #[test]
+    fn test_split_empty_daily_report() {
+        let report = DailyReport::new(String::from("2022-01-17"));
+        assert_eq!(report.clone().split(DailyReport::CHUNK_SIZE), vec![report]);
+    }
+
+    #[test]
+    fn test_daily_report_chunk_fits_packet() {
+        let mut report = DailyReport::new(String::from("2022-01-17"));
+        for id in 0..DailyReport::CHUNK_SIZE as i32 {
+            report.killmails.push(IdHashBinary::try_from((id, "1a38d4921711476e5ea304f799a1552b4d2e5d28")).unwrap());
+        }
+        let encoded = bincode::serialize(&CmdEvent::SaveDailyReport(report)).unwrap();
+        assert!(encoded.len() < 1024 * 1024);
+    }
+
     #[test]
     fn test_try_from() {
         let res = IdHashBinary::try_from((42, "1a38d4921711476e5ea304f799a1552b4d2e5d28"));
@@ -120,7 +205,7 @@ mod tests {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Killmail {
     pub killmail_id: i32,
     pub killmail_time: String,
@@ -129,6 +214,28 @@ pub struct Killmail {
     pub attackers: Vec<Attackers>,
     pub zkb: Option<Zkb>
 }
+impl Killmail {
+    /// The topics of the alliances, corporations and characters of the victim and the attackers
+    /// and of the solar system, each topic once
+    pub fn topics(&self, prefix: &str) -> Vec<String> {
+        let victim = (self.victim.alliance_id, self.victim.corporation_id, self.victim.character_id);
+        let attackers = self
+            .attackers
+            .iter()
+            .map(|attacker| (attacker.alliance_id, attacker.corporation_id, attacker.character_id));
+
+        let mut topics = BTreeSet::new();
+        for (alliance, corporation, character) in std::iter::once(victim).chain(attackers) {
+            for (kind, id) in [("alliance", alliance), ("corporation", corporation), ("character", character)] {
+                if let Some(id) = id {
+                    topics.insert(format!("{}/{}/{}", prefix, kind, id));
+                }
+            }
+        }
+        topics.insert(format!("{}/system/{}", prefix, self.solar_system_id));
+        topics.into_iter().collect()
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct Attackers {
@@ -149,9 +256,27 @@ pub struct Victim {
     pub ship_type_id: Option<i32>
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Zkb {
     pub hash: String,
+    #[serde(rename = "locationID", default)]
+    pub location_id: Option<i32>,
+    #[serde(rename = "fittedValue", default)]
+    pub fitted_value: Option<f64>,
+    #[serde(rename = "droppedValue", default)]
+    pub dropped_value: Option<f64>,
+    #[serde(rename = "destroyedValue", default)]
+    pub destroyed_value: Option<f64>,
+    #[serde(rename = "totalValue", default)]
+    pub total_value: Option<f64>,
+    #[serde(default)]
+    pub points: Option<i32>,
+    #[serde(default)]
+    pub npc: Option<bool>,
+    #[serde(default)]
+    pub solo: Option<bool>,
+    #[serde(default)]
+    pub awox: Option<bool>,
 }
 
 
@@ -177,6 +302,23 @@ mod tests_killmail {
 
     }
 
+    #[test]
+    fn test_killmail_topics() {
+        let buffer = std::fs::read_to_string("doc/killmail.json").unwrap();
+        let killmail = serde_json::from_str::<Killmail>(&buffer).unwrap();
+        let topics = killmail.topics(KILLS_TOPIC);
+
+        let count = |kind: &str| topics.iter().filter(|topic| topic.starts_with(&format!("zkb/kills/{}/", kind))).count();
+        assert_eq!(count("alliance"), 3);
+        assert_eq!(count("corporation"), 7);
+        assert_eq!(count("character"), 7);
+        assert_eq!(count("system"), 1);
+        assert_eq!(topics.len(), 18);
+        assert!(topics.contains(&String::from("zkb/kills/alliance/99010832")));
+        assert!(topics.contains(&String::from("zkb/kills/character/308241937")));
+        assert!(topics.contains(&String::from("zkb/kills/system/30001438")));
+    }
+
     #[test]
     fn test_zkb_killmail_deserialize() {
         let maybe_file = File::open("doc/zkb.json");
@@ -191,7 +333,11 @@ mod tests_killmail {
         assert_eq!(killmail.attackers.len(), 1);
         assert_eq!(killmail.victim.character_id, Some(2118847117));
         assert!(killmail.zkb.is_some());
-        assert_eq!(killmail.zkb.unwrap().hash, String::from("9377f28e34eabc18162e57e7e85f7a15c9339604"));
+        let zkb = killmail.zkb.unwrap();
+        assert_eq!(zkb.hash, String::from("9377f28e34eabc18162e57e7e85f7a15c9339604"));
+        assert_eq!(zkb.location_id, Some(50016271));
+        assert_eq!(zkb.total_value, Some(1402722.82));
+        assert_eq!(zkb.solo, Some(true));
 
     }
 }
\ No newline at end of file
@@ -0,0 +1,265 @@
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::api::{error, ApiError, Query};
+use crate::Killmail;
+
+/// The page that follows the live killmails with a filter form
+const PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Live killmails</title>
+<style>body { font-family: sans-serif; } input { width: 140px; } td { padding-right: 12px; }</style></head>
+<body><h1>Live killmails</h1>
+<form id="filter">
+<input name="character" placeholder="Character ids"> <input name="corporation" placeholder="Corporation ids">
+<input name="alliance" placeholder="Alliance ids"> <input name="system" placeholder="System ids">
+<input name="min_value" placeholder="Minimum ISK"> <button>Follow</button> <span id="state"></span>
+</form>
+<table><tbody id="killmails"></tbody></table>
+<script>
+let source = null;
+document.getElementById("filter").addEventListener("submit", (event) => {
+    event.preventDefault();
+    const params = new URLSearchParams();
+    for (const [name, value] of new FormData(event.target)) {
+        if (value.trim()) params.append(name, value.trim());
+    }
+    if (source) source.close();
+    source = new EventSource("/events?" + params);
+    source.onopen = () => document.getElementById("state").textContent = "following";
+    source.onerror = () => document.getElementById("state").textContent = "reconnecting";
+    source.addEventListener("killmail", (event) => {
+        const killmail = JSON.parse(event.data);
+        const row = document.createElement("tr");
+        const value = killmail.zkb && killmail.zkb.totalValue ? Math.round(killmail.zkb.totalValue).toLocaleString() : "-";
+        for (const text of [killmail.killmail_time, killmail.solar_system_id, killmail.victim.character_id || "-",
+                            killmail.victim.ship_type_id || "-", value, killmail.attackers.length]) {
+            const cell = document.createElement("td");
+            cell.textContent = text;
+            row.appendChild(cell);
+        }
+        const link = document.createElement("a");
+        link.href = "https://zkillboard.com/kill/" + killmail.killmail_id + "/";
+        link.textContent = "zkb";
+        row.appendChild(document.createElement("td")).appendChild(link);
+        const body = document.getElementById("killmails");
+        body.insertBefore(row, body.firstChild);
+        while (body.children.length > 200) body.removeChild(body.lastChild);
+    });
+});
+</script></body></html>
+"#;
+
+/// The killmails a client follows. The killmail has to involve any of the characters, corporations
+/// or alliances, happen in any of the systems and be worth at least `min_value`; empty conditions match all.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Filter {
+    pub characters: BTreeSet<i32>,
+    pub corporations: BTreeSet<i32>,
+    pub alliances: BTreeSet<i32>,
+    pub systems: BTreeSet<i32>,
+    pub min_value: Option<f64>,
+}
+impl Filter {
+    /// Parses `?character=1,2&corporation=3&alliance=4&system=30000142&min_value=1000000`
+    pub fn parse(query: &Query) -> Result<Self, ApiError> {
+        let ids = |name: &str| -> Result<BTreeSet<i32>, ApiError> {
+            query
+                .get(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| {
+                    id.parse()
+                        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid {} id '{}'", name, id)))
+                })
+                .collect()
+        };
+        let min_value = match query.get("min_value") {
+            Some(value) => Some(value.parse::<f64>().map_err(|_| {
+                ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid min_value '{}'", value))
+            })?),
+            None => None,
+        };
+        Ok(Self {
+            characters: ids("character")?,
+            corporations: ids("corporation")?,
+            alliances: ids("alliance")?,
+            systems: ids("system")?,
+            min_value,
+        })
+    }
+
+    pub fn matches(&self, killmail: &Killmail) -> bool {
+        let victim = &killmail.victim;
+        let participants = std::iter::once((victim.character_id, victim.corporation_id, victim.alliance_id)).chain(
+            killmail
+                .attackers
+                .iter()
+                .map(|attacker| (attacker.character_id, attacker.corporation_id, attacker.alliance_id)),
+        );
+        let contains = |ids: &BTreeSet<i32>, id: Option<i32>| id.is_some_and(|id| ids.contains(&id));
+        let entities = self.characters.is_empty() && self.corporations.is_empty() && self.alliances.is_empty()
+            || participants.into_iter().any(|(character, corporation, alliance)| {
+                contains(&self.characters, character)
+                    || contains(&self.corporations, corporation)
+                    || contains(&self.alliances, alliance)
+            });
+        let system = self.systems.is_empty() || self.systems.contains(&killmail.solar_system_id);
+        let value = self.min_value.is_none_or(|min_value| {
+            let total_value = killmail.zkb.as_ref().and_then(|zkb| zkb.total_value);
+            total_value.is_some_and(|total_value| total_value >= min_value)
+        });
+        entities && system && value
+    }
+}
+
+/// Pushes the live killmails to the browsers as Server-Sent Events
+#[derive(Clone)]
+pub struct Hub {
+    sender: broadcast::Sender<Arc<Killmail>>,
+    ping: Duration,
+}
+impl Hub {
+    /// A client more than `capacity` killmails behind skips them, `ping` keeps the idle connections open
+    pub fn new(capacity: usize, ping: Duration) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender, ping }
+    }
+
+    /// Sends the killmail to the clients and returns their number
+    pub fn publish(&self, killmail: Killmail) -> usize {
+        self.sender.send(Arc::new(killmail)).unwrap_or(0)
+    }
+
+    /// Answers the request of the path: `/` is the page, `/events?<filter>` is the event stream
+    pub fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET {
+            return error(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "Only GET is supported"));
+        }
+        match request.uri().path() {
+            "/" => Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(PAGE))
+                .unwrap_or_default(),
+            "/events" => match Filter::parse(&Query::parse(request.uri().query())) {
+                Ok(filter) => self.events(filter),
+                Err(e) => error(e),
+            },
+            path => error(ApiError::new(StatusCode::NOT_FOUND, format!("Unknown path {}", path))),
+        }
+    }
+
+    /// Streams the matching killmails as `killmail` events with the JSON killmail.
+    /// A client that falls behind gets a `lagged` event with the number of the skipped killmails.
+    fn events(&self, filter: Filter) -> Response<Body> {
+        let (mut sender, body) = Body::channel();
+        let mut killmails = self.sender.subscribe();
+        let ping = self.ping;
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + ping, ping);
+            if sender.send_data(": connected\n\n".into()).await.is_err() {
+                return;
+            }
+            loop {
+                let message = tokio::select! {
+                    received = killmails.recv() => match received {
+                        Ok(killmail) if filter.matches(&killmail) => match serde_json::to_string(killmail.as_ref()) {
+                            Ok(json) => format!("event: killmail\ndata: {}\n\n", json),
+                            Err(e) => {
+                                println!("Failed to encode killmail {}: {}", killmail.killmail_id, e);
+                                continue;
+                            }
+                        },
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            format!("event: lagged\ndata: {{\"skipped\":{}}}\n\n", skipped)
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = tick.tick() => String::from(": ping\n\n"),
+                };
+                if sender.send_data(message.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(body)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Zkb;
+    use hyper::body::HttpBody;
+
+    fn killmail(total_value: Option<f64>) -> Killmail {
+        let mut killmail = crate::storage::fixtures::killmail();
+        killmail.zkb = Some(Zkb {
+            hash: String::from("1a38d4921711476e5ea304f799a1552b4d2e5d28"),
+            location_id: None,
+            fitted_value: None,
+            dropped_value: None,
+            destroyed_value: None,
+            total_value,
+            points: None,
+            npc: None,
+            solo: None,
+            awox: None,
+        });
+        killmail
+    }
+
+    fn filter(query: &str) -> Filter {
+        Filter::parse(&Query::parse(Some(query))).unwrap()
+    }
+
+    #[test]
+    fn test_filter() {
+        let killmail = killmail(Some(1402722.82));
+        assert!(filter("").matches(&killmail));
+        // The victim's alliance or an attacker's corporation
+        assert!(filter("alliance=933731581").matches(&killmail));
+        assert!(filter("corporation=1,98676166").matches(&killmail));
+        assert!(!filter("character=1").matches(&killmail));
+        assert!(filter("character=1&alliance=99010832").matches(&killmail));
+        assert!(filter("system=30001438&min_value=1000000").matches(&killmail));
+        assert!(!filter("system=30000142").matches(&killmail));
+        assert!(!filter("min_value=2e6").matches(&killmail));
+        assert!(!filter("min_value=1").matches(&self::killmail(None)));
+
+        assert!(Filter::parse(&Query::parse(Some("system=jita"))).is_err());
+        assert!(Filter::parse(&Query::parse(Some("min_value=lots"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let hub = Hub::new(16, Duration::from_secs(60));
+        let request = Request::get("/events?min_value=1000000").body(Body::empty()).unwrap();
+        let response = hub.handle(request);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), ": connected\n\n");
+
+        assert_eq!(hub.publish(killmail(Some(1.0))), 1);
+        hub.publish(killmail(Some(1402722.82)));
+        let event = body.data().await.unwrap().unwrap();
+        let event = std::str::from_utf8(&event).unwrap();
+        assert!(event.starts_with("event: killmail\ndata: {\"killmail_id\":97318112,"));
+        assert!(event.contains("\"totalValue\":1402722.82"));
+        assert!(event.ends_with("}\n\n"));
+
+        drop(body);
+        assert_eq!(hub.handle(Request::get("/").body(Body::empty()).unwrap()).status(), StatusCode::OK);
+        let request = Request::get("/events?system=jita").body(Body::empty()).unwrap();
+        assert_eq!(hub.handle(request).status(), StatusCode::BAD_REQUEST);
+    }
+}
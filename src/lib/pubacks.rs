@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Matches the PUBACKs of QoS 1 publishes to the publish requests by the packet id.
+/// The client assigns the packet ids in the order of the requests, so a request waits
+/// in `queued` until its `Outgoing::Publish(pkid)`. A publish resent after a reconnect
+/// keeps its packet id, the broker may acknowledge in any order.
+pub struct PubAcks<T> {
+    queued: VecDeque<T>,
+    inflight: HashMap<u16, T>,
+}
+impl<T> Default for PubAcks<T> {
+    fn default() -> Self {
+        Self {
+            queued: VecDeque::new(),
+            inflight: HashMap::new(),
+        }
+    }
+}
+impl<T> PubAcks<T> {
+    /// Keeps the token of a publish request, must be called in the order the requests reach the client
+    pub fn queue(&mut self, token: T) {
+        self.queued.push_back(token);
+    }
+
+    /// Takes back the latest token when the client has refused its request
+    pub fn unqueue(&mut self) -> Option<T> {
+        self.queued.pop_back()
+    }
+
+    /// Handles `Outgoing::Publish(pkid)`, a resend of an unacknowledged packet id is ignored
+    pub fn sent(&mut self, pkid: u16) {
+        if self.inflight.contains_key(&pkid) {
+            return;
+        }
+        if let Some(token) = self.queued.pop_front() {
+            self.inflight.insert(pkid, token);
+        }
+    }
+
+    /// Handles `Incoming::PubAck(pkid)`, returns the token of the acknowledged request
+    pub fn acked(&mut self, pkid: u16) -> Option<T> {
+        self.inflight.remove(&pkid)
+    }
+
+    /// The number of the requests without a PUBACK
+    pub fn pending(&self) -> usize {
+        self.queued.len() + self.inflight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_order() {
+        let mut acks = PubAcks::default();
+        acks.queue("a");
+        acks.queue("b");
+        acks.sent(1);
+        acks.sent(2);
+        assert_eq!(acks.acked(1), Some("a"));
+        assert_eq!(acks.acked(2), Some("b"));
+        assert_eq!(acks.pending(), 0);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut acks = PubAcks::default();
+        acks.queue("a");
+        acks.queue("b");
+        acks.queue("c");
+        acks.sent(1);
+        acks.sent(2);
+        assert_eq!(acks.acked(2), Some("b"));
+        acks.sent(3);
+        assert_eq!(acks.acked(3), Some("c"));
+        assert_eq!(acks.acked(1), Some("a"));
+    }
+
+    #[test]
+    fn test_resend_after_reconnect() {
+        let mut acks = PubAcks::default();
+        acks.queue("a");
+        acks.queue("b");
+        acks.sent(1);
+        // The connection is lost, the client resends 1 before the next request
+        acks.sent(1);
+        acks.sent(2);
+        assert_eq!(acks.acked(1), Some("a"));
+        assert_eq!(acks.pending(), 1);
+        assert_eq!(acks.acked(2), Some("b"));
+    }
+
+    #[test]
+    fn test_unrelated_ack() {
+        let mut acks = PubAcks::default();
+        acks.queue("a");
+        assert_eq!(acks.acked(1), None);
+        acks.sent(1);
+        assert_eq!(acks.acked(7), None);
+        assert_eq!(acks.acked(1), Some("a"));
+        // A duplicate PUBACK confirms nothing
+        assert_eq!(acks.acked(1), None);
+    }
+
+    #[test]
+    fn test_reused_packet_id() {
+        let mut acks = PubAcks::default();
+        acks.queue("a");
+        acks.queue("b");
+        acks.sent(1);
+        assert_eq!(acks.acked(1), Some("a"));
+        acks.sent(1);
+        assert_eq!(acks.acked(1), Some("b"));
+    }
+
+    #[test]
+    fn test_refused_request() {
+        let mut acks = PubAcks::default();
+        acks.queue("a");
+        acks.queue("b");
+        assert_eq!(acks.unqueue(), Some("b"));
+        acks.sent(1);
+        assert_eq!(acks.acked(1), Some("a"));
+        assert_eq!(acks.pending(), 0);
+    }
+}
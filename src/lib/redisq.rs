@@ -0,0 +1,116 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::{Killmail, Zkb};
+
+#[derive(Deserialize)]
+struct Response {
+    package: Option<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    killmail: Killmail,
+    zkb: Zkb,
+}
+
+/// The RedisQ long-poll queue of zKillboard. The queue id keeps our position
+/// on the zKillboard side, so the killmails received while we were away are not lost.
+pub struct RedisQ {
+    client: reqwest::Client,
+    url: String,
+    queue_id: String,
+    ttw: u64,
+}
+impl RedisQ {
+    pub const URL: &'static str = "https://redisq.zkillboard.com/listen.php";
+
+    /// `ttw` is the number of seconds zKillboard waits for a killmail before an empty answer
+    pub fn new(url: &str, queue_id: &str, ttw: u64) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(ttw + 30))
+            .build()?;
+        Ok(Self {
+            client,
+            url: url.to_owned(),
+            queue_id: queue_id.to_owned(),
+            ttw,
+        })
+    }
+
+    /// Waits for the next killmail with the zkb data, None if the queue stayed empty
+    pub async fn next(&self) -> anyhow::Result<Option<Killmail>> {
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[("queueID", self.queue_id.as_str()), ("ttw", &self.ttw.to_string())])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("{} for {}", response.status(), self.url));
+        }
+        let response: Response = response.json().await?;
+        Ok(response.package.map(|package| {
+            let mut killmail = package.killmail;
+            killmail.zkb = Some(package.zkb);
+            killmail
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers the requests with the bodies in order and returns the request lines
+    async fn stand_in(bodies: Vec<String>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/listen.php", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for body in bodies {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = vec![0; 4096];
+                let len = socket.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..len]).to_string();
+                requests.push(request.lines().next().unwrap_or_default().to_owned());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_next() {
+        let package = std::fs::read_to_string("doc/redisq.json").unwrap();
+        let (url, handle) = stand_in(vec![package, String::from(r#"{"package":null}"#)]).await;
+        let queue = RedisQ::new(&url, "zkb_tools_test", 1).unwrap();
+
+        let killmail = queue.next().await.unwrap().unwrap();
+        assert_eq!(killmail.killmail_id, 98190688);
+        let zkb = killmail.zkb.unwrap();
+        assert_eq!(zkb.hash, "9377f28e34eabc18162e57e7e85f7a15c9339604");
+        assert_eq!(zkb.total_value, Some(1402722.82));
+        assert!(queue.next().await.unwrap().is_none());
+
+        let requests = handle.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("GET /listen.php?queueID=zkb_tools_test&ttw=1 "));
+    }
+
+    #[tokio::test]
+    async fn test_next_fails_on_broken_package() {
+        let (url, _handle) = stand_in(vec![String::from(r#"{"package":{"killID":1}}"#)]).await;
+        let queue = RedisQ::new(&url, "zkb_tools_test", 1).unwrap();
+        assert!(queue.next().await.is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::storage::{Entity, KillmailReader};
+
+/// The size of the buckets of a time series
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Hour,
+    Day,
+    Week,
+}
+impl Bucket {
+    /// The start of the bucket with the time, the weeks start on Monday
+    pub fn start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let date = time.date();
+        match self {
+            Bucket::Hour => date.and_hms(time.hour(), 0, 0),
+            Bucket::Day => date.and_hms(0, 0, 0),
+            Bucket::Week => (date - Duration::days(date.weekday().num_days_from_monday() as i64)).and_hms(0, 0, 0),
+        }
+    }
+
+    /// The start of the first bucket of a series of `days` up to `now`
+    pub fn first(&self, now: DateTime<Utc>, days: i64) -> DateTime<Utc> {
+        self.start(now - Duration::days(days))
+    }
+
+    pub fn duration(&self) -> Duration {
+        match self {
+            Bucket::Hour => Duration::hours(1),
+            Bucket::Day => Duration::days(1),
+            Bucket::Week => Duration::weeks(1),
+        }
+    }
+
+    fn label(&self, start: DateTime<Utc>) -> String {
+        match self {
+            Bucket::Hour => start.format("%Y-%m-%d %H:00").to_string(),
+            Bucket::Day | Bucket::Week => start.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+impl FromStr for Bucket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            _ => Err(anyhow!("Unknown bucket '{}', expected hour, day or week", s)),
+        }
+    }
+}
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bucket::Hour => write!(f, "hour"),
+            Bucket::Day => write!(f, "day"),
+            Bucket::Week => write!(f, "week"),
+        }
+    }
+}
+
+/// The kills and losses of one bucket
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+pub struct Point {
+    pub start: String,
+    pub kills: usize,
+    pub losses: usize,
+}
+
+/// The kills and losses of an entity per bucket, the buckets without killmails included
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+pub struct TimeSeries {
+    pub bucket: Bucket,
+    pub days: i64,
+    pub points: Vec<Point>,
+}
+impl TimeSeries {
+    /// Counts the killmail times returned by `KillmailReader::killmail_times`
+    /// in the buckets from `days` before `now` up to `now`
+    pub fn build(times: &[(String, bool)], now: DateTime<Utc>, days: i64, bucket: Bucket) -> Self {
+        let first = bucket.first(now, days);
+        let step = bucket.duration().num_seconds();
+        let len = (bucket.start(now) - first).num_seconds() / step + 1;
+        let mut points: Vec<_> = (0..len)
+            .map(|index| Point {
+                start: bucket.label(first + Duration::seconds(index * step)),
+                kills: 0,
+                losses: 0,
+            })
+            .collect();
+        for (time, is_loss) in times {
+            let time = match Utc.datetime_from_str(time, "%Y-%m-%dT%H:%M:%SZ") {
+                Ok(time) => time,
+                Err(_) => continue,
+            };
+            let index = (bucket.start(time) - first).num_seconds() / step;
+            if let Some(point) = usize::try_from(index).ok().and_then(|index| points.get_mut(index)) {
+                if *is_loss {
+                    point.losses += 1;
+                } else {
+                    point.kills += 1;
+                }
+            }
+        }
+        Self { bucket, days, points }
+    }
+
+    /// Queries the killmail times of the entity since the start of the first bucket
+    /// and builds the series of the last `days`
+    pub fn query(
+        reader: &mut dyn KillmailReader,
+        entity: Entity,
+        now: DateTime<Utc>,
+        days: i64,
+        bucket: Bucket,
+    ) -> anyhow::Result<Self> {
+        let first = bucket.first(now, days).format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let times = reader.killmail_times(entity, &first)?;
+        Ok(Self::build(&times, now, days, bucket))
+    }
+
+    pub fn kills(&self) -> Vec<usize> {
+        self.points.iter().map(|point| point.kills).collect()
+    }
+
+    pub fn losses(&self) -> Vec<usize> {
+        self.points.iter().map(|point| point.losses).collect()
+    }
+
+    /// A bar chart with the kills above and the losses below the axis
+    pub fn svg(&self, title: &str) -> String {
+        const WIDTH: f64 = 720.0;
+        const LEFT: f64 = 40.0;
+        const TOP: f64 = 28.0;
+        const HALF: f64 = 80.0;
+        let max = self.points.iter().map(|point| point.kills.max(point.losses)).max().unwrap_or(0).max(1);
+        let bar = WIDTH / self.points.len().max(1) as f64;
+        let axis = TOP + HALF;
+        let height = TOP + 2.0 * HALF + 24.0;
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="11">"#,
+            w = LEFT + WIDTH + 8.0,
+            h = height
+        );
+        svg.push_str(&format!(r#"<text x="{}" y="16" font-size="13">{}</text>"#, LEFT, escape(title)));
+        svg.push_str(&format!(r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#, LEFT - 4.0, TOP + 10.0, max));
+        svg.push_str(&format!(r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#, LEFT - 4.0, axis + HALF, max));
+        for (index, point) in self.points.iter().enumerate() {
+            let x = LEFT + index as f64 * bar;
+            let tooltip = format!("<title>{}: {} kills, {} losses</title>", point.start, point.kills, point.losses);
+            if point.kills > 0 {
+                let h = HALF * point.kills as f64 / max as f64;
+                svg.push_str(&format!(
+                    r##"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#2e7d32">{}</rect>"##,
+                    x,
+                    axis - h,
+                    bar,
+                    h,
+                    tooltip
+                ));
+            }
+            if point.losses > 0 {
+                let h = HALF * point.losses as f64 / max as f64;
+                svg.push_str(&format!(
+                    r##"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#c62828">{}</rect>"##,
+                    x, axis, bar, h, tooltip
+                ));
+            }
+        }
+        svg.push_str(&format!(
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="black"/>"#,
+            LEFT,
+            axis,
+            LEFT + WIDTH,
+            axis
+        ));
+        if let (Some(first), Some(last)) = (self.points.first(), self.points.last()) {
+            svg.push_str(&format!(r#"<text x="{}" y="{}">{}</text>"#, LEFT, height - 6.0, first.start));
+            svg.push_str(&format!(
+                r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#,
+                LEFT + WIDTH,
+                height - 6.0,
+                last.start
+            ));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+impl fmt::Display for TimeSeries {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let period = format!("({} days by {})", self.days, self.bucket);
+        writeln!(f, "{:<23}{} {}", "Kills Graph:", sparkline(&self.kills()), period)?;
+        writeln!(f, "{:<23}{} {}", "Losses Graph:", sparkline(&self.losses()), period)
+    }
+}
+
+/// The values as the block characters, the zeros as the lowest block
+pub fn sparkline(values: &[usize]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    values
+        .iter()
+        .map(|&value| if value == 0 { BLOCKS[0] } else { BLOCKS[1 + (value * 7 - 1) / max] })
+        .collect()
+}
+
+/// Escapes the text for HTML and SVG
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analytics::fixtures::now;
+    use crate::storage::fixtures;
+
+    fn times() -> Vec<(String, bool)> {
+        vec![
+            (String::from("2022-01-10T10:00:00Z"), false),
+            (String::from("2022-01-10T10:30:00Z"), false),
+            (String::from("2022-01-20T20:00:00Z"), false),
+            (String::from("2022-01-29T21:00:00Z"), true),
+            (String::from("broken"), true),
+        ]
+    }
+
+    #[test]
+    fn test_bucket_start() {
+        let time = Utc.ymd(2022, 1, 13).and_hms(10, 30, 15);
+        assert_eq!(Bucket::Hour.start(time), Utc.ymd(2022, 1, 13).and_hms(10, 0, 0));
+        assert_eq!(Bucket::Day.start(time), Utc.ymd(2022, 1, 13).and_hms(0, 0, 0));
+        // Thursday to Monday
+        assert_eq!(Bucket::Week.start(time), Utc.ymd(2022, 1, 10).and_hms(0, 0, 0));
+        assert_eq!("week".parse::<Bucket>().unwrap(), Bucket::Week);
+        assert!("month".parse::<Bucket>().is_err());
+    }
+
+    #[test]
+    fn test_build() {
+        let series = TimeSeries::build(&times(), now(), 30, Bucket::Day);
+        assert_eq!(series.points.len(), 31);
+        assert_eq!(series.points[0].start, "2022-01-01");
+        assert_eq!(series.points[30].start, "2022-01-31");
+        assert_eq!(series.points[9], Point { start: String::from("2022-01-10"), kills: 2, losses: 0 });
+        assert_eq!(series.kills().iter().sum::<usize>(), 3);
+        assert_eq!(series.losses()[28], 1);
+
+        let series = TimeSeries::build(&times(), now(), 30, Bucket::Week);
+        assert_eq!(series.points[0].start, "2021-12-27");
+        let counts: Vec<_> = series.points.iter().map(|point| (point.kills, point.losses)).collect();
+        assert_eq!(counts, vec![(0, 0), (0, 0), (2, 0), (1, 0), (0, 1), (0, 0)]);
+
+        let series = TimeSeries::build(&times(), now(), 30, Bucket::Hour);
+        assert_eq!(series.points.len(), 30 * 24 + 1);
+        assert_eq!(series.points.iter().find(|point| point.kills == 2).unwrap().start, "2022-01-10 10:00");
+    }
+
+    #[test]
+    fn test_query_first_bucket() {
+        let mut store = fixtures::store();
+
+        // The loss at 2021-12-12T15:46:42Z is in the first day, before the cutoff of 1 day
+        let now = Utc.ymd(2021, 12, 13).and_hms(16, 0, 0);
+        let series = TimeSeries::query(&mut store, Entity::Character(308241937), now, 1, Bucket::Day).unwrap();
+        assert_eq!(series.points[0], Point { start: String::from("2021-12-12"), kills: 0, losses: 1 });
+        let series = TimeSeries::query(&mut store, Entity::Character(308241937), now, 1, Bucket::Hour).unwrap();
+        assert_eq!(series.losses().iter().sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0, 1, 4, 8, 2]), "▁▂▅█▃");
+        assert_eq!(sparkline(&[0, 0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+
+    #[test]
+    fn test_svg_and_text() {
+        let series = TimeSeries::build(&times(), now(), 30, Bucket::Week);
+        let svg = series.svg("Kills & losses of <SO>");
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains("Kills &amp; losses of &lt;SO&gt;"));
+        assert_eq!(svg.matches("<rect ").count(), 3);
+        assert!(svg.contains("<title>2022-01-10: 2 kills, 0 losses</title>"));
+
+        let text = series.to_string();
+        assert_eq!(text, "Kills Graph:           ▁▁█▅▁▁ (30 days by week)\nLosses Graph:          ▁▁▁▁█▁ (30 days by week)\n");
+    }
+}
@@ -0,0 +1,36 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+/// Answers the requests with `handle` on `listen` until Ctrl-C,
+/// every request is logged with the status of its response
+pub async fn serve<H, F>(listen: SocketAddr, handle: H) -> anyhow::Result<()>
+where
+    H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    let service = make_service_fn(move |_| {
+        let handle = handle.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let line = format!("{} {}", request.method(), request.uri());
+                let response = handle(request);
+                async move {
+                    let response = response.await;
+                    println!("{} {}", line, response.status().as_u16());
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&listen)?.serve(service);
+    println!("Listening on http://{}", listen);
+    server
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
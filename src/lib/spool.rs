@@ -0,0 +1,399 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The counters of a spool since it was opened
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SpoolStats {
+    pub spooled: u64,
+    pub drained: u64,
+    pub dropped: u64,
+    pub pending: u64,
+    pub bytes: u64,
+}
+impl fmt::Display for SpoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pending {} ({} bytes), spooled {}, drained {}, dropped {}",
+            self.pending, self.bytes, self.spooled, self.drained, self.dropped
+        )
+    }
+}
+
+/// An append-only file of messages on their way to the MQTT server.
+/// Every record is a little-endian `u32` length followed by the payload.
+/// The records are sent in order and stay in the spool until they are acknowledged,
+/// the offset of the acknowledged records is kept in `<spool>.offset` across restarts.
+/// The file is truncated once every record is acknowledged.
+pub struct Spool {
+    path: PathBuf,
+    offset_path: PathBuf,
+    file: File,
+    read_offset: u64,
+    send_offset: u64,
+    size: u64,
+    max_size: u64,
+    next_seq: u64,
+    /// The sequence number of the first record in `sent`
+    first_sent: u64,
+    /// The lengths of the sent records that wait for an ack and whether they are acknowledged
+    sent: VecDeque<(u64, bool)>,
+    stats: SpoolStats,
+}
+impl Spool {
+    const HEADER: u64 = 4;
+
+    /// Opens the spool and keeps the records left by the previous run that were not acknowledged.
+    /// A record cut by a crash at the end of the file is discarded.
+    pub fn open<P: Into<PathBuf>>(path: P, max_size: u64) -> io::Result<Self> {
+        let path = path.into();
+        let mut offset_path = OsString::from(path.as_os_str());
+        offset_path.push(".offset");
+        let offset_path = PathBuf::from(offset_path);
+        let saved = match std::fs::read_to_string(&offset_path) {
+            Ok(offset) => offset.trim().parse().unwrap_or(0),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut size = 0;
+        let mut read_offset = 0;
+        let mut pending = 0;
+        while let Some(record) = Self::record_len(&mut file, size, len)? {
+            size += Self::HEADER + record;
+            pending += 1;
+            if size == saved {
+                read_offset = size;
+                pending = 0;
+            }
+        }
+        if size < len {
+            println!("Discarded {} bytes of a broken record in {}", len - size, path.display());
+            file.set_len(size)?;
+        }
+        let stats = SpoolStats {
+            pending,
+            bytes: size - read_offset,
+            ..SpoolStats::default()
+        };
+        Ok(Self {
+            path,
+            offset_path,
+            file,
+            read_offset,
+            send_offset: read_offset,
+            size,
+            max_size,
+            next_seq: 0,
+            first_sent: 0,
+            sent: VecDeque::new(),
+            stats,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// True when every record is acknowledged
+    pub fn is_empty(&self) -> bool {
+        self.stats.pending == 0
+    }
+
+    /// The number of the records that were not passed to the sender yet
+    pub fn unsent(&self) -> u64 {
+        self.stats.pending - self.sent.len() as u64
+    }
+
+    pub fn stats(&self) -> &SpoolStats {
+        &self.stats
+    }
+
+    /// Appends the payload, returns false if it was dropped because the spool is full
+    pub fn push(&mut self, payload: &[u8]) -> io::Result<bool> {
+        let record = Self::HEADER + payload.len() as u64;
+        if self.size + record > self.max_size && self.read_offset > 0 {
+            self.compact()?;
+        }
+        if self.size + record > self.max_size {
+            self.stats.dropped += 1;
+            return Ok(false);
+        }
+        let mut buffer = Vec::with_capacity(record as usize);
+        buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(payload);
+        self.file.write_all(&buffer)?;
+        self.file.flush()?;
+        self.size += record;
+        self.stats.spooled += 1;
+        self.stats.pending += 1;
+        self.stats.bytes = self.size - self.read_offset;
+        Ok(true)
+    }
+
+    /// Passes the records that were not sent yet in order to `send` with their sequence number
+    /// until it returns false, that record is passed again by the next call.
+    /// The records stay in the spool until `ack` is called with their sequence number.
+    /// Returns the number of the sent records.
+    pub fn drain<F>(&mut self, mut send: F) -> io::Result<usize>
+    where
+        F: FnMut(u64, &[u8]) -> bool,
+    {
+        let mut count = 0;
+        while let Some(len) = Self::record_len(&mut self.file, self.send_offset, self.size)? {
+            let mut payload = vec![0; len as usize];
+            self.file.read_exact(&mut payload)?;
+            if !send(self.next_seq, &payload) {
+                break;
+            }
+            self.next_seq += 1;
+            self.send_offset += Self::HEADER + len;
+            self.sent.push_back((Self::HEADER + len, false));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Marks the sent record as acknowledged. The spool advances over the acknowledged
+    /// records at its beginning, so a record acknowledged out of order waits for the earlier ones.
+    pub fn ack(&mut self, seq: u64) -> io::Result<()> {
+        match seq.checked_sub(self.first_sent).and_then(|index| self.sent.get_mut(index as usize)) {
+            Some((_, acked)) => *acked = true,
+            None => return Ok(()),
+        }
+        let mut advanced = false;
+        while let Some(&(len, true)) = self.sent.front() {
+            self.sent.pop_front();
+            self.first_sent += 1;
+            self.read_offset += len;
+            self.stats.drained += 1;
+            self.stats.pending -= 1;
+            advanced = true;
+        }
+        if !advanced {
+            return Ok(());
+        }
+        if self.read_offset == self.size {
+            // The smaller offset is saved first, a crash in between replays the records instead of losing them
+            self.save_offset(0)?;
+            self.file.set_len(0)?;
+            self.read_offset = 0;
+            self.send_offset = 0;
+            self.size = 0;
+        } else {
+            self.save_offset(self.read_offset)?;
+        }
+        self.stats.bytes = self.size - self.read_offset;
+        Ok(())
+    }
+
+    fn save_offset(&self, offset: u64) -> io::Result<()> {
+        std::fs::write(&self.offset_path, offset.to_string())
+    }
+
+    /// Reads the length of the record at `offset`, None if there is no complete record
+    fn record_len(file: &mut File, offset: u64, end: u64) -> io::Result<Option<u64>> {
+        if offset + Self::HEADER > end {
+            return Ok(None);
+        }
+        let mut header = [0; Self::HEADER as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header) as u64;
+        if offset + Self::HEADER + len > end {
+            return Ok(None);
+        }
+        Ok(Some(len))
+    }
+
+    /// Moves the records that are not acknowledged to the beginning of the file
+    fn compact(&mut self) -> io::Result<()> {
+        let mut pending = Vec::with_capacity((self.size - self.read_offset) as usize);
+        self.file.seek(SeekFrom::Start(self.read_offset))?;
+        self.file.read_to_end(&mut pending)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, &pending)?;
+        self.save_offset(0)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.send_offset -= self.read_offset;
+        self.size = pending.len() as u64;
+        self.read_offset = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("zkb_tools_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(offset_path(&path));
+        path
+    }
+
+    fn offset_path(path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.offset", path.display()))
+    }
+
+    fn remove(path: PathBuf) {
+        std::fs::remove_file(offset_path(&path)).unwrap_or_default();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Sends the records and acknowledges them at once
+    fn drain_all(spool: &mut Spool) -> Vec<Vec<u8>> {
+        let mut sent = Vec::new();
+        spool
+            .drain(|seq, payload| {
+                sent.push((seq, payload.to_vec()));
+                true
+            })
+            .unwrap();
+        for (seq, _) in &sent {
+            spool.ack(*seq).unwrap();
+        }
+        sent.into_iter().map(|(_, payload)| payload).collect()
+    }
+
+    #[test]
+    fn test_drain_in_order() {
+        let path = temp_path("order.spool");
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        for payload in [&b"first"[..], b"second", b""] {
+            assert!(spool.push(payload).unwrap());
+        }
+        assert_eq!(spool.stats().pending, 3);
+
+        assert_eq!(drain_all(&mut spool), vec![b"first".to_vec(), b"second".to_vec(), Vec::new()]);
+        assert!(spool.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        remove(path);
+    }
+
+    #[test]
+    fn test_drain_stops_on_failure() {
+        let path = temp_path("failure.spool");
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+
+        let mut sent = 0;
+        let count = spool
+            .drain(|seq, _| {
+                sent += 1;
+                assert_eq!(seq, sent - 1);
+                sent < 2
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(spool.unsent(), 1);
+        spool.ack(0).unwrap();
+        spool.push(b"third").unwrap();
+        assert_eq!(drain_all(&mut spool), vec![b"second".to_vec(), b"third".to_vec()]);
+        remove(path);
+    }
+
+    #[test]
+    fn test_records_wait_for_ack() {
+        let path = temp_path("ack.spool");
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+        spool.push(b"third").unwrap();
+        assert_eq!(spool.drain(|_, _| true).unwrap(), 3);
+        assert_eq!(spool.unsent(), 0);
+        // Sent records are not passed again
+        assert_eq!(spool.drain(|_, _| true).unwrap(), 0);
+
+        // An ack out of order waits for the first record
+        spool.ack(1).unwrap();
+        assert_eq!(spool.stats().pending, 3);
+        spool.ack(0).unwrap();
+        assert_eq!(spool.stats().pending, 1);
+        assert_eq!(spool.stats().drained, 2);
+        // An unknown ack changes nothing
+        spool.ack(7).unwrap();
+        assert_eq!(spool.stats().pending, 1);
+
+        // The unacknowledged record is replayed after a restart
+        drop(spool);
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        assert_eq!(spool.stats().pending, 1);
+        assert_eq!(drain_all(&mut spool), vec![b"third".to_vec()]);
+        assert!(spool.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        remove(path);
+    }
+
+    #[test]
+    fn test_reopen_keeps_records() {
+        let path = temp_path("reopen.spool");
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+        drop(spool);
+        // A record cut by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        assert_eq!(spool.stats().pending, 2);
+        assert_eq!(drain_all(&mut spool), vec![b"first".to_vec(), b"second".to_vec()]);
+        remove(path);
+    }
+
+    #[test]
+    fn test_reopen_skips_acknowledged() {
+        let path = temp_path("offset.spool");
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+        spool.drain(|seq, _| seq == 0).unwrap();
+        spool.ack(0).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        assert_eq!(spool.stats().pending, 1);
+        assert_eq!(drain_all(&mut spool), vec![b"second".to_vec()]);
+        drop(spool);
+
+        // An offset that is not at a record boundary replays the whole spool
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        spool.push(b"third").unwrap();
+        std::fs::write(offset_path(&path), "3").unwrap();
+        drop(spool);
+        let mut spool = Spool::open(&path, 1024).unwrap();
+        assert_eq!(drain_all(&mut spool), vec![b"third".to_vec()]);
+        remove(path);
+    }
+
+    #[test]
+    fn test_size_cap() {
+        let path = temp_path("cap.spool");
+        let mut spool = Spool::open(&path, 20).unwrap();
+        assert!(spool.push(b"12345678").unwrap());
+        assert!(!spool.push(b"123456789").unwrap());
+        assert!(spool.push(b"1234").unwrap());
+        assert_eq!(spool.stats().dropped, 1);
+
+        // The acknowledged space is reused, the sent record keeps its sequence number
+        spool.drain(|_, payload| payload.len() == 8).unwrap();
+        spool.ack(0).unwrap();
+        spool.drain(|_, _| true).unwrap();
+        assert!(spool.push(b"12345678").unwrap());
+        spool.ack(1).unwrap();
+        assert_eq!(drain_all(&mut spool), vec![b"12345678".to_vec()]);
+        assert!(spool.is_empty());
+        remove(path);
+    }
+}
@@ -0,0 +1,423 @@
+use anyhow::anyhow;
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::{DailyReport, IdHash, Killmail};
+
+mod pgsql;
+mod sqlite;
+
+pub use pgsql::{PgHashStore, PgKillmailStore};
+pub use sqlite::{SqliteHashStore, SqliteKillmailStore};
+
+/// The origin of the stored copy of a killmail
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Source {
+    Esi,
+    Websocket,
+    Archive,
+}
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Esi => "esi",
+            Source::Websocket => "websocket",
+            Source::Archive => "archive",
+        }
+    }
+}
+
+/// Storage of the killmails and their participants
+pub trait KillmailStore: Send {
+    /// Upserts the killmails in a single transaction and returns their ids.
+    /// A copy with zkb metadata fills in the missing fields of the stored one
+    /// and becomes its `source`, a copy without it never overwrites anything.
+    fn insert_killmails(&mut self, killmails: Vec<Killmail>, source: Source) -> anyhow::Result<Vec<i32>>;
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Storage of the killmail hashes and their processing state
+pub trait HashStore: Send {
+    /// Inserts the hashes of the report in a single transaction
+    fn insert_report(&mut self, report: DailyReport) -> anyhow::Result<usize>;
+    /// Returns up to `count` hashes with the highest ids that are not complete yet
+    fn query_hashes(&mut self, count: u32) -> anyhow::Result<Vec<IdHash>>;
+    /// Marks the killmails as complete and returns the number of updated rows
+    fn mark_complete(&mut self, ids: &[i32]) -> anyhow::Result<usize>;
+    /// Inserts the hash of the killmail that is already stored
+    fn save_handled_hash(&mut self, id: i32, hash: String) -> anyhow::Result<()>;
+}
+
+/// The level the participants are aggregated at
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Level {
+    Character,
+    Corporation,
+    Alliance,
+}
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Character => "character",
+            Level::Corporation => "corporation",
+            Level::Alliance => "alliance",
+        }
+    }
+
+    pub fn entity(&self, id: i32) -> Entity {
+        match self {
+            Level::Character => Entity::Character(id),
+            Level::Corporation => Entity::Corporation(id),
+            Level::Alliance => Entity::Alliance(id),
+        }
+    }
+
+    /// The character, corporation or alliance id of the participant
+    pub fn id_of(&self, participant: &StoredParticipant) -> Option<i32> {
+        match self {
+            Level::Character => participant.character_id,
+            Level::Corporation => participant.corporation_id,
+            Level::Alliance => participant.alliance_id,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Level::Character => "character_id",
+            Level::Corporation => "corporation_id",
+            Level::Alliance => "alliance_id",
+        }
+    }
+}
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "character" => Ok(Level::Character),
+            "corporation" => Ok(Level::Corporation),
+            "alliance" => Ok(Level::Alliance),
+            _ => Err(anyhow!("Unknown level '{}', expected character, corporation or alliance", s)),
+        }
+    }
+}
+
+/// The side of the killmails of an entity its opponents are on
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Side {
+    /// The victims of the kills of the entity
+    Victim,
+    /// The attackers on the losses of the entity
+    Attacker,
+}
+
+/// What the opponents are counted by
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Group {
+    Level(Level),
+    ShipType,
+}
+impl Group {
+    fn column(&self) -> &'static str {
+        match self {
+            Group::Level(level) => level.column(),
+            Group::ShipType => "ship_type_id",
+        }
+    }
+}
+
+/// The participant the reports are built for
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Entity {
+    Character(i32),
+    Corporation(i32),
+    Alliance(i32),
+}
+impl Entity {
+    pub fn id(&self) -> i32 {
+        match self {
+            Entity::Character(id) | Entity::Corporation(id) | Entity::Alliance(id) => *id,
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        match self {
+            Entity::Character(_) => Level::Character,
+            Entity::Corporation(_) => Level::Corporation,
+            Entity::Alliance(_) => Level::Alliance,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.level().name()
+    }
+
+    /// Whether the participant is the character or a member of the corporation or the alliance
+    pub fn matches(&self, participant: &StoredParticipant) -> bool {
+        self.level().id_of(participant) == Some(self.id())
+    }
+
+    fn column(&self) -> &'static str {
+        self.level().column()
+    }
+}
+
+/// A stored killmail with the victim and the attackers in the ESI order
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct StoredKillmail {
+    pub killmail_id: i32,
+    pub killmail_time: String,
+    pub solar_system_id: i32,
+    pub total_value: Option<f64>,
+    pub participants: Vec<StoredParticipant>,
+}
+impl StoredKillmail {
+    pub fn victim(&self) -> Option<&StoredParticipant> {
+        self.participants.iter().find(|participant| participant.is_victim)
+    }
+
+    pub fn attackers(&self) -> impl Iterator<Item = &StoredParticipant> {
+        self.participants.iter().filter(|participant| !participant.is_victim)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct StoredParticipant {
+    pub character_id: Option<i32>,
+    pub corporation_id: Option<i32>,
+    pub alliance_id: Option<i32>,
+    pub ship_type_id: Option<i32>,
+    pub damage: i32,
+    pub is_victim: bool,
+}
+
+/// Read access to the stored killmails for the reports
+pub trait KillmailReader: Send {
+    /// The killmails since `since` (YYYY-MM-DDTHH:MM:SSZ) with the entity among the participants, oldest first
+    fn killmails_of(&mut self, entity: Entity, since: &str) -> anyhow::Result<Vec<StoredKillmail>>;
+
+    /// The time of every killmail since `since` with the entity among the participants
+    /// and whether the entity is the victim, oldest first
+    fn killmail_times(&mut self, entity: Entity, since: &str) -> anyhow::Result<Vec<(String, bool)>>;
+
+    /// The killmail with the participants, None if it is not stored
+    fn killmail(&mut self, killmail_id: i32) -> anyhow::Result<Option<StoredKillmail>>;
+
+    /// A page of the killmails with the entity among the participants, newest first
+    fn latest_killmails_of(&mut self, entity: Entity, offset: usize, limit: usize) -> anyhow::Result<Vec<StoredKillmail>>;
+
+    /// The latest killmails of all entities, newest first
+    fn latest_killmails(&mut self, limit: usize) -> anyhow::Result<Vec<StoredKillmail>>;
+
+    /// The ids of the level with the most kills since `since` and their number of kills
+    fn top_attackers(&mut self, level: Level, since: &str, limit: usize) -> anyhow::Result<Vec<(i32, usize)>>;
+
+    /// The most frequent opponents of the entity since `since` on the side, grouped by the ids
+    /// of the group, with the number of the killmails they appear on
+    fn opponents(
+        &mut self,
+        entity: Entity,
+        side: Side,
+        group: Group,
+        since: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i32, usize)>>;
+}
+
+/// The killmails matching the filter, a row per participant, the victim first.
+/// Both backends accept `$N`, SQLite numbers them in the order of appearance,
+/// so the parameters have to appear in order.
+fn killmails_query(filter: &str, order: &str) -> String {
+    format!(
+        "SELECT k.killmail_id, k.killmail_time, k.solar_system_id, k.total_value,
+                p.character_id, p.corporation_id, p.alliance_id, p.ship_type_id, p.damage, p.is_victim
+         FROM killmails k JOIN participants p ON p.killmail_id = k.killmail_id
+         WHERE {}
+         ORDER BY {}, p.is_victim DESC, p.attacker_index",
+        filter, order
+    )
+}
+
+/// The query of `KillmailReader::killmails_of`, `$1` is the entity id and `$2` the lower bound of the time
+fn killmails_of_query(entity: Entity) -> String {
+    let filter = format!(
+        "k.killmail_id IN (SELECT killmail_id FROM participants WHERE {} = $1) AND k.killmail_time >= $2",
+        entity.column()
+    );
+    killmails_query(&filter, "k.killmail_time, k.killmail_id")
+}
+
+/// The query of `KillmailReader::killmail`, `$1` is the killmail id
+fn killmail_query() -> String {
+    killmails_query("k.killmail_id = $1", "k.killmail_id")
+}
+
+/// The query of `KillmailReader::latest_killmails_of`, `$1` is the entity id, `$2` the limit and `$3` the offset
+fn latest_killmails_of_query(entity: Entity) -> String {
+    let filter = format!(
+        "k.killmail_id IN (
+            SELECT l.killmail_id FROM killmails l
+            WHERE l.killmail_id IN (SELECT killmail_id FROM participants WHERE {} = $1)
+            ORDER BY l.killmail_time DESC, l.killmail_id DESC
+            LIMIT $2 OFFSET $3
+         )",
+        entity.column()
+    );
+    killmails_query(&filter, "k.killmail_time DESC, k.killmail_id DESC")
+}
+
+/// The query of `KillmailReader::latest_killmails`, `$1` is the limit
+fn latest_killmails_query() -> String {
+    let filter = "k.killmail_id IN (
+            SELECT killmail_id FROM killmails ORDER BY killmail_time DESC, killmail_id DESC LIMIT $1
+         )";
+    killmails_query(filter, "k.killmail_time DESC, k.killmail_id DESC")
+}
+
+/// The query of `KillmailReader::killmail_times`, with the parameters of `killmails_of_query`
+fn killmail_times_query(entity: Entity) -> String {
+    format!(
+        "SELECT k.killmail_time, MAX(p.is_victim)
+         FROM killmails k JOIN participants p ON p.killmail_id = k.killmail_id
+         WHERE p.{} = $1
+           AND k.killmail_time >= $2
+         GROUP BY k.killmail_id, k.killmail_time
+         ORDER BY k.killmail_time, k.killmail_id",
+        entity.column()
+    )
+}
+
+/// The query of `KillmailReader::top_attackers`, `$1` is the lower bound of the time and `$2` the limit
+fn top_attackers_query(level: Level) -> String {
+    format!(
+        "SELECT p.{column}, COUNT(DISTINCT p.killmail_id)
+         FROM participants p JOIN killmails k ON k.killmail_id = p.killmail_id
+         WHERE k.killmail_time >= $1
+           AND p.is_victim = 0
+           AND p.{column} IS NOT NULL
+         GROUP BY p.{column}
+         ORDER BY 2 DESC, 1
+         LIMIT $2",
+        column = level.column()
+    )
+}
+
+/// The query of `KillmailReader::opponents`, the rows of the entity joined with the rows of the opponents
+/// of the same killmail. `$1` is the entity id, `$2` the lower bound of the time and `$3` the limit.
+fn opponents_query(entity: Entity, side: Side, group: Group) -> String {
+    // The entity is on the other side of the killmail
+    let (own, opponent) = match side {
+        Side::Victim => (0, 1),
+        Side::Attacker => (1, 0),
+    };
+    format!(
+        "SELECT o.{group}, COUNT(DISTINCT o.killmail_id)
+         FROM participants e
+         JOIN participants o ON o.killmail_id = e.killmail_id AND o.is_victim = {opponent}
+         JOIN killmails k ON k.killmail_id = e.killmail_id
+         WHERE e.{entity} = $1
+           AND e.is_victim = {own}
+           AND k.killmail_time >= $2
+           AND o.{group} IS NOT NULL
+         GROUP BY o.{group}
+         ORDER BY 2 DESC, 1
+         LIMIT $3",
+        group = group.column(),
+        opponent = opponent,
+        entity = entity.column(),
+        own = own
+    )
+}
+
+/// Appends the participant row to its killmail, the rows of a killmail are adjacent
+fn push_row(killmails: &mut Vec<StoredKillmail>, killmail: StoredKillmail, participant: StoredParticipant) {
+    match killmails.last_mut() {
+        Some(last) if last.killmail_id == killmail.killmail_id => last.participants.push(participant),
+        _ => {
+            let mut killmail = killmail;
+            killmail.participants.push(participant);
+            killmails.push(killmail);
+        }
+    }
+}
+
+fn is_postgres(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+/// Opens the killmail store: a `postgres://` URL or a path to the SQLite file
+pub fn open_killmail_store(url: &str) -> anyhow::Result<Box<dyn KillmailStore>> {
+    if is_postgres(url) {
+        Ok(Box::new(PgKillmailStore::open(url)?))
+    } else {
+        Ok(Box::new(SqliteKillmailStore::open(url)?))
+    }
+}
+
+/// Opens the killmail store for the reports: a `postgres://` URL or a path to the SQLite file
+pub fn open_killmail_reader(url: &str) -> anyhow::Result<Box<dyn KillmailReader>> {
+    if is_postgres(url) {
+        Ok(Box::new(PgKillmailStore::open(url)?))
+    } else {
+        Ok(Box::new(SqliteKillmailStore::open(url)?))
+    }
+}
+
+/// Opens the hash store: a `postgres://` URL or a path to the SQLite file
+pub fn open_hash_store(url: &str) -> anyhow::Result<Box<dyn HashStore>> {
+    if is_postgres(url) {
+        Ok(Box::new(PgHashStore::open(url)?))
+    } else {
+        Ok(Box::new(SqliteHashStore::open(url)?))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{KillmailStore, Source, SqliteKillmailStore};
+    use crate::{DailyReport, IdHashBinary, Killmail, Zkb};
+    use std::convert::TryFrom;
+    use std::fs::File;
+
+    pub fn killmail() -> Killmail {
+        let file = File::open("doc/killmail.json").unwrap();
+        serde_json::from_reader(file).unwrap()
+    }
+
+    /// An in-memory store with the killmail of `doc/killmail.json`
+    pub fn store() -> SqliteKillmailStore {
+        let mut store = SqliteKillmailStore::open(":memory:").unwrap();
+        store.insert_killmails(vec![killmail()], Source::Esi).unwrap();
+        store
+    }
+
+    pub fn zkb() -> Zkb {
+        Zkb {
+            hash: String::from("1a38d4921711476e5ea304f799a1552b4d2e5d28"),
+            location_id: Some(50016271),
+            fitted_value: Some(1327809.86),
+            dropped_value: Some(160905.63),
+            destroyed_value: Some(1241817.19),
+            total_value: Some(1402722.82),
+            points: Some(1),
+            npc: Some(false),
+            solo: Some(true),
+            awox: Some(false),
+        }
+    }
+
+    pub fn report() -> DailyReport {
+        let mut report = DailyReport::new(String::from("2022-01-17"));
+        for (id, hash) in [
+            (1, "1a38d4921711476e5ea304f799a1552b4d2e5d28"),
+            (2, "9377f28e34eabc18162e57e7e85f7a15c9339604"),
+            (3, "0000000000000000000000000000000000000003"),
+        ] {
+            report.killmails.push(IdHashBinary::try_from((id, hash)).unwrap());
+        }
+        report
+    }
+}
@@ -0,0 +1,501 @@
+use anyhow::anyhow;
+use postgres::types::ToSql;
+use postgres::{Client, NoTls, Transaction};
+
+use super::{
+    killmail_query, killmail_times_query, killmails_of_query, latest_killmails_of_query, latest_killmails_query,
+    opponents_query, push_row, timestamp, top_attackers_query, Entity, Group, HashStore, KillmailReader,
+    KillmailStore, Level, Side, Source, StoredKillmail, StoredParticipant,
+};
+use crate::{DailyReport, IdHash, IdHashBinary, Killmail};
+
+const KILLMAILS_SCHEMA: &str = "killmails";
+const KILLMAILS_VERSION: i32 = 3;
+const HASHES_SCHEMA: &str = "hashes";
+const HASHES_VERSION: i32 = 1;
+
+pub struct PgKillmailStore {
+    client: Client,
+}
+impl PgKillmailStore {
+    pub fn open(url: &str) -> anyhow::Result<Self> {
+        Self::new(Client::connect(url, NoTls)?)
+    }
+
+    fn new(mut client: Client) -> anyhow::Result<Self> {
+        migrate(&mut client, KILLMAILS_SCHEMA, KILLMAILS_VERSION, migrate_killmails)?;
+        Ok(Self { client })
+    }
+}
+impl KillmailStore for PgKillmailStore {
+    fn insert_killmails(&mut self, killmails: Vec<Killmail>, source: Source) -> anyhow::Result<Vec<i32>> {
+        let mut transaction = self.client.transaction()?;
+        let ids = fetch_and_insert(killmails, source, &mut transaction)?;
+        transaction.commit().map_err(|e| anyhow!(format!("{}", e)))?;
+        Ok(ids)
+    }
+}
+
+impl KillmailReader for PgKillmailStore {
+    fn killmails_of(&mut self, entity: Entity, since: &str) -> anyhow::Result<Vec<StoredKillmail>> {
+        self.query_killmails(&killmails_of_query(entity), &[&entity.id(), &since])
+    }
+
+    fn killmail_times(&mut self, entity: Entity, since: &str) -> anyhow::Result<Vec<(String, bool)>> {
+        let rows = self.client.query(killmail_times_query(entity).as_str(), &[&entity.id(), &since])?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get::<_, i32>(1) != 0)).collect())
+    }
+
+    fn killmail(&mut self, killmail_id: i32) -> anyhow::Result<Option<StoredKillmail>> {
+        Ok(self.query_killmails(&killmail_query(), &[&killmail_id])?.pop())
+    }
+
+    fn latest_killmails_of(&mut self, entity: Entity, offset: usize, limit: usize) -> anyhow::Result<Vec<StoredKillmail>> {
+        let query = latest_killmails_of_query(entity);
+        self.query_killmails(&query, &[&entity.id(), &(limit as i64), &(offset as i64)])
+    }
+
+    fn latest_killmails(&mut self, limit: usize) -> anyhow::Result<Vec<StoredKillmail>> {
+        self.query_killmails(&latest_killmails_query(), &[&(limit as i64)])
+    }
+
+    fn top_attackers(&mut self, level: Level, since: &str, limit: usize) -> anyhow::Result<Vec<(i32, usize)>> {
+        let rows = self.client.query(top_attackers_query(level).as_str(), &[&since, &(limit as i64)])?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get::<_, i64>(1) as usize)).collect())
+    }
+
+    fn opponents(
+        &mut self,
+        entity: Entity,
+        side: Side,
+        group: Group,
+        since: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i32, usize)>> {
+        let query = opponents_query(entity, side, group);
+        let rows = self.client.query(query.as_str(), &[&entity.id(), &since, &(limit as i64)])?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get::<_, i64>(1) as usize)).collect())
+    }
+}
+impl PgKillmailStore {
+    fn query_killmails(&mut self, query: &str, params: &[&(dyn ToSql + Sync)]) -> anyhow::Result<Vec<StoredKillmail>> {
+        let mut killmails = Vec::new();
+        for row in self.client.query(query, params)? {
+            let killmail = StoredKillmail {
+                killmail_id: row.get(0),
+                killmail_time: row.get(1),
+                solar_system_id: row.get(2),
+                total_value: row.get(3),
+                participants: Vec::new(),
+            };
+            let participant = StoredParticipant {
+                character_id: row.get(4),
+                corporation_id: row.get(5),
+                alliance_id: row.get(6),
+                ship_type_id: row.get(7),
+                damage: row.get(8),
+                is_victim: row.get::<_, i32>(9) != 0,
+            };
+            push_row(&mut killmails, killmail, participant);
+        }
+        Ok(killmails)
+    }
+}
+
+/// The key of the advisory lock the migrations of all the stores hold
+const MIGRATION_LOCK: i64 = 0x7a6b_625f_7363_6865;
+
+/// Applies the missing migrations of the named schema under an exclusive lock,
+/// so several hosts may start against the same database
+fn migrate(
+    client: &mut Client,
+    name: &str,
+    target: i32,
+    apply: fn(&mut Transaction, i32) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // The lock comes first, so the processes starting on an empty database
+    // do not race to create the version table itself
+    let mut transaction = client.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+    transaction.batch_execute("
+        CREATE TABLE IF NOT EXISTS schema_version(
+            name TEXT NOT NULL PRIMARY KEY,
+            version INTEGER NOT NULL
+        );
+    ")?;
+    let version: i32 = transaction
+        .query_opt("SELECT version FROM schema_version WHERE name = $1", &[&name])?
+        .map(|row| row.get(0))
+        .unwrap_or(0);
+    if version >= target {
+        return Ok(());
+    }
+
+    apply(&mut transaction, version)?;
+    transaction.execute(
+        "INSERT INTO schema_version (name, version) VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE SET version = EXCLUDED.version",
+        &[&name, &target],
+    )?;
+    transaction.commit().map_err(|e| anyhow!(format!("{}", e)))
+}
+
+fn migrate_killmails(transaction: &mut Transaction, version: i32) -> anyhow::Result<()> {
+    if version < 1 {
+        transaction.batch_execute("
+            CREATE TABLE IF NOT EXISTS killmails(
+                killmail_id INTEGER NOT NULL PRIMARY KEY,
+                killmail_time TEXT NOT NULL,
+                solar_system_id INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS killmail_time_idx ON killmails(killmail_time);
+
+            CREATE TABLE IF NOT EXISTS participants(
+                killmail_id INTEGER NOT NULL,
+                character_id INTEGER,
+                corporation_id INTEGER,
+                alliance_id INTEGER,
+                ship_type_id INTEGER,
+                damage INTEGER NOT NULL,
+                is_victim INTEGER NOT NULL,
+                attacker_index INTEGER NOT NULL,
+                PRIMARY KEY(killmail_id, is_victim, attacker_index),
+                FOREIGN KEY(killmail_id) REFERENCES killmails(killmail_id)
+            );
+            CREATE INDEX IF NOT EXISTS participant_idx ON participants(character_id, corporation_id, alliance_id);
+        ")?;
+    }
+    if version < 2 {
+        transaction.batch_execute("
+            ALTER TABLE killmails
+                ADD COLUMN zkb_hash TEXT,
+                ADD COLUMN location_id INTEGER,
+                ADD COLUMN fitted_value DOUBLE PRECISION,
+                ADD COLUMN dropped_value DOUBLE PRECISION,
+                ADD COLUMN destroyed_value DOUBLE PRECISION,
+                ADD COLUMN total_value DOUBLE PRECISION,
+                ADD COLUMN points INTEGER,
+                ADD COLUMN npc INTEGER,
+                ADD COLUMN solo INTEGER,
+                ADD COLUMN awox INTEGER,
+                ADD COLUMN source TEXT,
+                ADD COLUMN updated_at TEXT;
+        ")?;
+    }
+    if version < 3 {
+        transaction.batch_execute("
+            CREATE INDEX IF NOT EXISTS participant_corporation_idx ON participants(corporation_id);
+            CREATE INDEX IF NOT EXISTS participant_alliance_idx ON participants(alliance_id);
+        ")?;
+    }
+    Ok(())
+}
+
+fn migrate_hashes(transaction: &mut Transaction, version: i32) -> anyhow::Result<()> {
+    if version < 1 {
+        transaction.batch_execute("
+            CREATE TABLE IF NOT EXISTS hashes(
+                id INTEGER PRIMARY KEY NOT NULL,
+                hash BYTEA NOT NULL,
+                state INTEGER NOT NULL DEFAULT 0
+            );
+        ")?;
+    }
+    Ok(())
+}
+
+fn fetch_and_insert(killmails: Vec<Killmail>, source: Source, transaction: &mut Transaction)-> anyhow::Result<Vec<i32>> {
+    const INSERT_KILLMAIL: &str = r"INSERT INTO killmails (
+            killmail_id, killmail_time, solar_system_id,
+            zkb_hash, location_id, fitted_value, dropped_value, destroyed_value, total_value,
+            points, npc, solo, awox, source, updated_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        ON CONFLICT (killmail_id) DO UPDATE SET
+            zkb_hash = EXCLUDED.zkb_hash,
+            location_id = COALESCE(EXCLUDED.location_id, killmails.location_id),
+            fitted_value = COALESCE(EXCLUDED.fitted_value, killmails.fitted_value),
+            dropped_value = COALESCE(EXCLUDED.dropped_value, killmails.dropped_value),
+            destroyed_value = COALESCE(EXCLUDED.destroyed_value, killmails.destroyed_value),
+            total_value = COALESCE(EXCLUDED.total_value, killmails.total_value),
+            points = COALESCE(EXCLUDED.points, killmails.points),
+            npc = COALESCE(EXCLUDED.npc, killmails.npc),
+            solo = COALESCE(EXCLUDED.solo, killmails.solo),
+            awox = COALESCE(EXCLUDED.awox, killmails.awox),
+            source = EXCLUDED.source,
+            updated_at = EXCLUDED.updated_at
+        WHERE EXCLUDED.zkb_hash IS NOT NULL";
+
+    const INSERT_PARTICIPANT: &str = r"INSERT INTO participants VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (killmail_id, is_victim, attacker_index) DO UPDATE SET
+            character_id = COALESCE(participants.character_id, EXCLUDED.character_id),
+            corporation_id = COALESCE(participants.corporation_id, EXCLUDED.corporation_id),
+            alliance_id = COALESCE(participants.alliance_id, EXCLUDED.alliance_id),
+            ship_type_id = COALESCE(participants.ship_type_id, EXCLUDED.ship_type_id)";
+
+    let insert_killmail_stmt = transaction.prepare(INSERT_KILLMAIL)?;
+    let insert_participant_stmt = transaction.prepare(INSERT_PARTICIPANT)?;
+
+    let mut ids = Vec::new();
+    for killmail in killmails {
+        let id = killmail.killmail_id;
+
+        let zkb = killmail.zkb.as_ref();
+        let flag = |value: Option<bool>| value.map(i32::from);
+        transaction.execute(&insert_killmail_stmt, &[
+            &killmail.killmail_id,
+            &killmail.killmail_time,
+            &killmail.solar_system_id,
+            &zkb.map(|zkb| zkb.hash.clone()),
+            &zkb.and_then(|zkb| zkb.location_id),
+            &zkb.and_then(|zkb| zkb.fitted_value),
+            &zkb.and_then(|zkb| zkb.dropped_value),
+            &zkb.and_then(|zkb| zkb.destroyed_value),
+            &zkb.and_then(|zkb| zkb.total_value),
+            &zkb.and_then(|zkb| zkb.points),
+            &flag(zkb.and_then(|zkb| zkb.npc)),
+            &flag(zkb.and_then(|zkb| zkb.solo)),
+            &flag(zkb.and_then(|zkb| zkb.awox)),
+            &source.name(),
+            &timestamp(),
+        ])?;
+
+        let victim = killmail.victim;
+        transaction.execute(&insert_participant_stmt, &[
+            &killmail.killmail_id,
+            &victim.character_id,
+            &victim.corporation_id,
+            &victim.alliance_id,
+            &victim.ship_type_id,
+            &victim.damage_taken,
+            &1_i32,
+            &0_i32,
+        ])?;
+
+        for (index, attacker) in killmail.attackers.into_iter().enumerate() {
+            transaction.execute(&insert_participant_stmt, &[
+                &killmail.killmail_id,
+                &attacker.character_id,
+                &attacker.corporation_id,
+                &attacker.alliance_id,
+                &attacker.ship_type_id,
+                &attacker.damage_done,
+                &0_i32,
+                &(index as i32),
+            ])?;
+        }
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+pub struct PgHashStore {
+    client: Client,
+}
+impl PgHashStore {
+    pub fn open(url: &str) -> anyhow::Result<Self> {
+        Self::new(Client::connect(url, NoTls)?)
+    }
+
+    fn new(mut client: Client) -> anyhow::Result<Self> {
+        migrate(&mut client, HASHES_SCHEMA, HASHES_VERSION, migrate_hashes)?;
+        Ok(Self { client })
+    }
+}
+impl HashStore for PgHashStore {
+    fn insert_report(&mut self, report: DailyReport) -> anyhow::Result<usize> {
+        let mut transaction = self.client.transaction()?;
+        let stmt = transaction.prepare("INSERT INTO hashes (id, hash) VALUES ($1, $2) ON CONFLICT DO NOTHING")?;
+        let mut count = 0;
+        for id_hash in report.killmails {
+            transaction.execute(&stmt, &[&id_hash.get_id(), &&id_hash.get_hash()[..]])?;
+            count += 1;
+        }
+        transaction
+            .commit()
+            .map(|()|{count})
+            .map_err(|e| anyhow!(format!("{}", e)))
+    }
+
+    fn query_hashes(&mut self, count: u32) -> anyhow::Result<Vec<IdHash>> {
+        let rows = self.client.query(
+            "SELECT id, hash FROM hashes WHERE state = 0 ORDER BY id DESC LIMIT $1",
+            &[&i64::from(count)],
+        )?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            let id: i32 = row.get(0);
+            let blob: Vec<u8> = row.get(1);
+            result.push((id, IdHashBinary::hash_to_string(&blob[..])));
+        }
+        Ok(result)
+    }
+
+    fn mark_complete(&mut self, ids: &[i32]) -> anyhow::Result<usize> {
+        let count = self.client.execute("UPDATE hashes SET state = 1 WHERE id = ANY($1)", &[&ids])?;
+        Ok(count as usize)
+    }
+
+    fn save_handled_hash(&mut self, id: i32, hash: String) -> anyhow::Result<()> {
+        let blob = IdHashBinary::string_to_hash(hash)?;
+        self.client.execute(
+            "INSERT INTO hashes (id, hash, state) VALUES ($1, $2, 1) ON CONFLICT DO NOTHING",
+            &[&id, &blob],
+        )?;
+        Ok(())
+    }
+}
+
+/// The tests are ignored by default, they need `ZKB_TEST_POSTGRES` with the URL of a disposable database:
+/// `ZKB_TEST_POSTGRES=postgres://postgres@localhost/zkb_test cargo test -- --ignored`.
+/// Every test works in its own schema.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::fixtures;
+
+    fn url() -> String {
+        std::env::var("ZKB_TEST_POSTGRES").expect("ZKB_TEST_POSTGRES is not set")
+    }
+
+    fn connect(schema: &str) -> Client {
+        let mut client = Client::connect(&url(), NoTls).unwrap();
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}; SET search_path TO {0};",
+                schema
+            ))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    #[ignore]
+    fn test_insert_killmails_keeps_attacker_order_and_duplicates() {
+        let client = connect("test_pg_insert_killmails");
+        let mut store = PgKillmailStore::new(client).unwrap();
+        let mut killmail = fixtures::killmail();
+        killmail.attackers.push(killmail.attackers[1].clone());
+        let expected: Vec<_> = killmail.attackers.iter().map(|a| (a.character_id, a.damage_done)).collect();
+
+        assert_eq!(store.insert_killmails(vec![killmail.clone()], Source::Esi).unwrap(), vec![97318112]);
+        assert_eq!(store.insert_killmails(vec![killmail], Source::Esi).unwrap(), vec![97318112]);
+
+        let rows = store.client.query(
+            "SELECT character_id, damage FROM participants WHERE killmail_id = $1 AND is_victim = 0 ORDER BY attacker_index",
+            &[&97318112_i32],
+        ).unwrap();
+        let actual: Vec<(Option<i32>, i32)> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_insert_killmails_merges_zkb_metadata() {
+        let client = connect("test_pg_merge_killmails");
+        let mut store = PgKillmailStore::new(client).unwrap();
+        let esi = fixtures::killmail();
+        let mut websocket = esi.clone();
+        websocket.zkb = Some(fixtures::zkb());
+
+        store.insert_killmails(vec![esi.clone()], Source::Esi).unwrap();
+        store.insert_killmails(vec![websocket], Source::Websocket).unwrap();
+        store.insert_killmails(vec![esi], Source::Esi).unwrap();
+
+        let row = store.client.query_one(
+            "SELECT zkb_hash, total_value, solo, source FROM killmails WHERE killmail_id = $1",
+            &[&97318112_i32],
+        ).unwrap();
+        assert_eq!(row.get::<_, Option<String>>(0), Some(fixtures::zkb().hash));
+        assert_eq!(row.get::<_, Option<f64>>(1), Some(1402722.82));
+        assert_eq!(row.get::<_, Option<i32>>(2), Some(1));
+        assert_eq!(row.get::<_, String>(3), "websocket");
+    }
+
+    #[test]
+    #[ignore]
+    fn test_concurrent_migrations() {
+        connect("test_pg_concurrent_migrations");
+        let url = url();
+        let threads: Vec<_> = (0..4)
+            .map(|index| {
+                let url = url.clone();
+                std::thread::spawn(move || {
+                    let mut client = Client::connect(&url, NoTls).unwrap();
+                    client.batch_execute("SET search_path TO test_pg_concurrent_migrations").unwrap();
+                    if index % 2 == 0 {
+                        PgKillmailStore::new(client).map(|_| ())
+                    } else {
+                        PgHashStore::new(client).map(|_| ())
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_migrate_is_idempotent() {
+        let client = connect("test_pg_migrate");
+        let mut store = PgKillmailStore::new(client).unwrap();
+        migrate(&mut store.client, KILLMAILS_SCHEMA, KILLMAILS_VERSION, migrate_killmails).unwrap();
+        let row = store.client
+            .query_one("SELECT version FROM schema_version WHERE name = $1", &[&KILLMAILS_SCHEMA])
+            .unwrap();
+        assert_eq!(row.get::<_, i32>(0), KILLMAILS_VERSION);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_killmails_of() {
+        let client = connect("test_pg_killmails_of");
+        let mut store = PgKillmailStore::new(client).unwrap();
+        store.insert_killmails(vec![fixtures::killmail()], Source::Esi).unwrap();
+
+        let killmails = store.killmails_of(Entity::Character(2112698901), "2021-12-01T00:00:00Z").unwrap();
+        assert_eq!(killmails.len(), 1);
+        assert_eq!(killmails[0].participants.len(), 8);
+        assert_eq!(killmails[0].victim().unwrap().character_id, Some(308241937));
+        assert_eq!(killmails[0].attackers().nth(2).unwrap().character_id, Some(2116032618));
+
+        assert!(store.killmails_of(Entity::Character(2112698901), "2022-01-01T00:00:00Z").unwrap().is_empty());
+
+        let times = store.killmail_times(Entity::Alliance(933731581), "2021-12-01T00:00:00Z").unwrap();
+        assert_eq!(times, vec![(String::from("2021-12-12T15:46:42Z"), true)]);
+        let times = store.killmail_times(Entity::Corporation(98676166), "2021-12-01T00:00:00Z").unwrap();
+        assert_eq!(times, vec![(String::from("2021-12-12T15:46:42Z"), false)]);
+
+        let killmail = store.killmail(97318112).unwrap().unwrap();
+        assert_eq!(store.latest_killmails_of(Entity::Corporation(98676166), 0, 10).unwrap(), vec![killmail]);
+        assert!(store.latest_killmails_of(Entity::Corporation(98676166), 1, 10).unwrap().is_empty());
+        let top = store.top_attackers(Level::Corporation, "2021-12-01T00:00:00Z", 3).unwrap();
+        assert_eq!(top, vec![(1000162, 1), (98340855, 1), (98418288, 1)]);
+
+        let since = "2021-12-01T00:00:00Z";
+        let corporations = Group::Level(Level::Corporation);
+        let victims = store.opponents(Entity::Alliance(99010832), Side::Victim, corporations, since, 10);
+        assert_eq!(victims.unwrap(), vec![(98052179, 1)]);
+        let ships = store.opponents(Entity::Alliance(933731581), Side::Attacker, Group::ShipType, since, 2);
+        assert_eq!(ships.unwrap(), vec![(2212, 1), (17728, 1)]);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_hash_store_lifecycle() {
+        let client = connect("test_pg_hash_store");
+        let mut store = PgHashStore::new(client).unwrap();
+        assert_eq!(store.insert_report(fixtures::report()).unwrap(), 3);
+        let hashes = store.query_hashes(2).unwrap();
+        assert_eq!(hashes.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(hashes[1].1, "9377f28e34eabc18162e57e7e85f7a15c9339604");
+
+        assert_eq!(store.mark_complete(&[3, 2, 100]).unwrap(), 2);
+        store.save_handled_hash(4, String::from("1a38d4921711476e5ea304f799a1552b4d2e5d28")).unwrap();
+        assert_eq!(store.query_hashes(10).unwrap(), vec![(1, String::from("1a38d4921711476e5ea304f799a1552b4d2e5d28"))]);
+    }
+}
@@ -0,0 +1,555 @@
+use anyhow::anyhow;
+use rusqlite::{named_params, params, Connection, Params, Transaction};
+
+use super::{
+    killmail_query, killmail_times_query, killmails_of_query, latest_killmails_of_query, latest_killmails_query,
+    opponents_query, push_row, timestamp, top_attackers_query, Entity, Group, HashStore, KillmailReader,
+    KillmailStore, Level, Side, Source, StoredKillmail, StoredParticipant,
+};
+use crate::{DailyReport, IdHash, IdHashBinary, Killmail};
+
+const SCHEMA_VERSION: i32 = 3;
+
+pub struct SqliteKillmailStore {
+    conn: Connection,
+}
+impl SqliteKillmailStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;").map_err(|e| anyhow!(e))?;
+        migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+}
+impl KillmailStore for SqliteKillmailStore {
+    fn insert_killmails(&mut self, killmails: Vec<Killmail>, source: Source) -> anyhow::Result<Vec<i32>> {
+        let transaction = self.conn.transaction()?;
+        let ids = fetch_and_insert(killmails, source, &transaction)?;
+        transaction.commit().map_err(|e| anyhow!(format!("{}", e)))?;
+        Ok(ids)
+    }
+}
+
+impl KillmailReader for SqliteKillmailStore {
+    fn killmails_of(&mut self, entity: Entity, since: &str) -> anyhow::Result<Vec<StoredKillmail>> {
+        self.query_killmails(&killmails_of_query(entity), params![entity.id(), since])
+    }
+
+    fn killmail_times(&mut self, entity: Entity, since: &str) -> anyhow::Result<Vec<(String, bool)>> {
+        let mut stmt = self.conn.prepare(&killmail_times_query(entity))?;
+        let rows = stmt.query_map(params![entity.id(), since], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn killmail(&mut self, killmail_id: i32) -> anyhow::Result<Option<StoredKillmail>> {
+        Ok(self.query_killmails(&killmail_query(), params![killmail_id])?.pop())
+    }
+
+    fn latest_killmails_of(&mut self, entity: Entity, offset: usize, limit: usize) -> anyhow::Result<Vec<StoredKillmail>> {
+        let query = latest_killmails_of_query(entity);
+        self.query_killmails(&query, params![entity.id(), limit as i64, offset as i64])
+    }
+
+    fn latest_killmails(&mut self, limit: usize) -> anyhow::Result<Vec<StoredKillmail>> {
+        self.query_killmails(&latest_killmails_query(), params![limit as i64])
+    }
+
+    fn top_attackers(&mut self, level: Level, since: &str, limit: usize) -> anyhow::Result<Vec<(i32, usize)>> {
+        let mut stmt = self.conn.prepare(&top_attackers_query(level))?;
+        let rows = stmt.query_map(params![since, limit as i64], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn opponents(
+        &mut self,
+        entity: Entity,
+        side: Side,
+        group: Group,
+        since: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i32, usize)>> {
+        let mut stmt = self.conn.prepare(&opponents_query(entity, side, group))?;
+        let rows = stmt.query_map(params![entity.id(), since, limit as i64], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as usize))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+impl SqliteKillmailStore {
+    fn query_killmails<P: Params>(&self, query: &str, params: P) -> anyhow::Result<Vec<StoredKillmail>> {
+        let mut stmt = self.conn.prepare(query)?;
+        let mut rows = stmt.query(params)?;
+        let mut killmails = Vec::new();
+        while let Some(row) = rows.next()? {
+            let killmail = StoredKillmail {
+                killmail_id: row.get(0)?,
+                killmail_time: row.get(1)?,
+                solar_system_id: row.get(2)?,
+                total_value: row.get(3)?,
+                participants: Vec::new(),
+            };
+            let participant = StoredParticipant {
+                character_id: row.get(4)?,
+                corporation_id: row.get(5)?,
+                alliance_id: row.get(6)?,
+                ship_type_id: row.get(7)?,
+                damage: row.get(8)?,
+                is_victim: row.get(9)?,
+            };
+            push_row(&mut killmails, killmail, participant);
+        }
+        Ok(killmails)
+    }
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let transaction = conn.transaction()?;
+    if version < 1 {
+        migrate_v1(&transaction)?;
+    }
+    if version < 2 {
+        migrate_v2(&transaction)?;
+    }
+    if version < 3 {
+        migrate_v3(&transaction)?;
+    }
+    transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    transaction.commit().map_err(|e| anyhow!(format!("{}", e)))
+}
+
+fn migrate_v1(transaction: &Transaction) -> anyhow::Result<()> {
+    let legacy: bool = transaction.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'participants'",
+        [],
+        |row| row.get(0),
+    )?;
+    if legacy {
+        // Version 0 kept no attacker order and merged rows of the same character.
+        // The rows were inserted in the ESI order, so rowid restores the index.
+        transaction.execute_batch("
+            DROP INDEX IF EXISTS participant_idx;
+            ALTER TABLE participants RENAME TO participants_v0;
+        ").map_err(|e| anyhow!(e))?;
+    }
+
+    transaction.execute_batch("
+        CREATE TABLE IF NOT EXISTS killmails(
+            killmail_id INTEGER NOT NULL PRIMARY KEY,
+            killmail_time TEXT NOT NULL,
+            solar_system_id INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS killmail_time_idx ON killmails(killmail_time);
+
+        CREATE TABLE IF NOT EXISTS participants(
+            killmail_id INTEGER NOT NULL,
+            character_id INTEGER,
+            corporation_id INTEGER,
+            alliance_id INTEGER,
+            ship_type_id INTEGER,
+            damage INTEGER NOT NULL,
+            is_victim INTEGER NOT NULL,
+            attacker_index INTEGER NOT NULL,
+            PRIMARY KEY(killmail_id, is_victim, attacker_index),
+            FOREIGN KEY(killmail_id) REFERENCES killmails(killmail_id)
+        );
+        CREATE INDEX IF NOT EXISTS participant_idx ON participants(character_id, corporation_id, alliance_id);
+    ").map_err(|e| anyhow!(e))?;
+
+    if legacy {
+        transaction.execute_batch("
+            INSERT INTO participants
+            SELECT killmail_id, character_id, corporation_id, alliance_id, ship_type_id, damage, is_victim,
+                ROW_NUMBER() OVER (PARTITION BY killmail_id, is_victim ORDER BY rowid) - 1
+            FROM participants_v0;
+            DROP TABLE participants_v0;
+        ").map_err(|e| anyhow!(e))?;
+    }
+    Ok(())
+}
+
+fn migrate_v2(transaction: &Transaction) -> anyhow::Result<()> {
+    transaction.execute_batch("
+        ALTER TABLE killmails ADD COLUMN zkb_hash TEXT;
+        ALTER TABLE killmails ADD COLUMN location_id INTEGER;
+        ALTER TABLE killmails ADD COLUMN fitted_value REAL;
+        ALTER TABLE killmails ADD COLUMN dropped_value REAL;
+        ALTER TABLE killmails ADD COLUMN destroyed_value REAL;
+        ALTER TABLE killmails ADD COLUMN total_value REAL;
+        ALTER TABLE killmails ADD COLUMN points INTEGER;
+        ALTER TABLE killmails ADD COLUMN npc INTEGER;
+        ALTER TABLE killmails ADD COLUMN solo INTEGER;
+        ALTER TABLE killmails ADD COLUMN awox INTEGER;
+        ALTER TABLE killmails ADD COLUMN source TEXT;
+        ALTER TABLE killmails ADD COLUMN updated_at TEXT;
+    ").map_err(|e| anyhow!(e))
+}
+
+/// The corporation and alliance reports look the participants up by these columns alone
+fn migrate_v3(transaction: &Transaction) -> anyhow::Result<()> {
+    transaction.execute_batch("
+        CREATE INDEX IF NOT EXISTS participant_corporation_idx ON participants(corporation_id);
+        CREATE INDEX IF NOT EXISTS participant_alliance_idx ON participants(alliance_id);
+    ").map_err(|e| anyhow!(e))
+}
+
+fn fetch_and_insert(killmails: Vec<Killmail>, source: Source, transaction: &Transaction)-> anyhow::Result<Vec<i32>> {
+    const INSERT_KILLMAIL: &str = r"INSERT INTO killmails (
+            killmail_id, killmail_time, solar_system_id,
+            zkb_hash, location_id, fitted_value, dropped_value, destroyed_value, total_value,
+            points, npc, solo, awox, source, updated_at
+        ) VALUES (
+            :killmail_id, :killmail_time, :solar_system_id,
+            :zkb_hash, :location_id, :fitted_value, :dropped_value, :destroyed_value, :total_value,
+            :points, :npc, :solo, :awox, :source, :updated_at
+        ) ON CONFLICT(killmail_id) DO UPDATE SET
+            zkb_hash = excluded.zkb_hash,
+            location_id = COALESCE(excluded.location_id, killmails.location_id),
+            fitted_value = COALESCE(excluded.fitted_value, killmails.fitted_value),
+            dropped_value = COALESCE(excluded.dropped_value, killmails.dropped_value),
+            destroyed_value = COALESCE(excluded.destroyed_value, killmails.destroyed_value),
+            total_value = COALESCE(excluded.total_value, killmails.total_value),
+            points = COALESCE(excluded.points, killmails.points),
+            npc = COALESCE(excluded.npc, killmails.npc),
+            solo = COALESCE(excluded.solo, killmails.solo),
+            awox = COALESCE(excluded.awox, killmails.awox),
+            source = excluded.source,
+            updated_at = excluded.updated_at
+        WHERE excluded.zkb_hash IS NOT NULL";
+
+    const INSERT_PARTICIPANT: &str = r"INSERT INTO participants VALUES (
+            :killmail_id,
+            :character_id,
+            :corporation_id,
+            :alliance_id,
+            :ship_type_id,
+            :damage,
+            :is_victim,
+            :attacker_index
+        ) ON CONFLICT(killmail_id, is_victim, attacker_index) DO UPDATE SET
+            character_id = COALESCE(participants.character_id, excluded.character_id),
+            corporation_id = COALESCE(participants.corporation_id, excluded.corporation_id),
+            alliance_id = COALESCE(participants.alliance_id, excluded.alliance_id),
+            ship_type_id = COALESCE(participants.ship_type_id, excluded.ship_type_id)";
+
+    let mut insert_killmail_stmt = transaction.prepare(INSERT_KILLMAIL)?;
+    let mut insert_participant_stmt = transaction.prepare(INSERT_PARTICIPANT)?;
+
+    let mut ids = Vec::new();
+    for killmail in killmails {
+        let id = killmail.killmail_id;
+
+        let zkb = killmail.zkb;
+        insert_killmail_stmt.execute(named_params! {
+            ":killmail_id": killmail.killmail_id,
+            ":killmail_time": killmail.killmail_time,
+            ":solar_system_id": killmail.solar_system_id,
+            ":zkb_hash": zkb.as_ref().map(|zkb| zkb.hash.clone()),
+            ":location_id": zkb.as_ref().and_then(|zkb| zkb.location_id),
+            ":fitted_value": zkb.as_ref().and_then(|zkb| zkb.fitted_value),
+            ":dropped_value": zkb.as_ref().and_then(|zkb| zkb.dropped_value),
+            ":destroyed_value": zkb.as_ref().and_then(|zkb| zkb.destroyed_value),
+            ":total_value": zkb.as_ref().and_then(|zkb| zkb.total_value),
+            ":points": zkb.as_ref().and_then(|zkb| zkb.points),
+            ":npc": zkb.as_ref().and_then(|zkb| zkb.npc),
+            ":solo": zkb.as_ref().and_then(|zkb| zkb.solo),
+            ":awox": zkb.as_ref().and_then(|zkb| zkb.awox),
+            ":source": source.name(),
+            ":updated_at": timestamp()
+        })?;
+
+        let victim = killmail.victim;
+        insert_participant_stmt.execute(named_params!{
+            ":killmail_id": killmail.killmail_id,
+            ":character_id": victim.character_id,
+            ":corporation_id": victim.corporation_id,
+            ":alliance_id": victim.alliance_id,
+            ":ship_type_id": victim.ship_type_id,
+            ":damage": victim.damage_taken,
+            ":is_victim": 1,
+            ":attacker_index": 0
+        })?;
+
+        for (index, attacker) in killmail.attackers.into_iter().enumerate() {
+            insert_participant_stmt.execute(named_params!{
+                ":killmail_id": killmail.killmail_id,
+                ":character_id": attacker.character_id,
+                ":corporation_id": attacker.corporation_id,
+                ":alliance_id": attacker.alliance_id,
+                ":ship_type_id": attacker.ship_type_id,
+                ":damage": attacker.damage_done,
+                ":is_victim": 0,
+                ":attacker_index": index as i32
+            })?;
+        }
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+pub struct SqliteHashStore {
+    conn: Connection,
+}
+impl SqliteHashStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS hashes(
+                id INTEGER PRIMARY KEY NOT NULL,
+                hash BLOB NOT NULL,
+                state INTEGER NOT NULL DEFAULT 0
+            );",
+            [],
+        )
+        .map_err(|e| anyhow!(e))?;
+        Ok(Self { conn })
+    }
+}
+impl HashStore for SqliteHashStore {
+    fn insert_report(&mut self, report: DailyReport) -> anyhow::Result<usize> {
+        let transaction = self.conn.transaction()?;
+        let count = insert_impl(report, &transaction)?;
+        transaction
+            .commit()
+            .map(|()|{count})
+            .map_err(|e| anyhow!(format!("{}", e)))
+    }
+
+    fn query_hashes(&mut self, count: u32) -> anyhow::Result<Vec<IdHash>> {
+        let mut select_stmt = self.conn.prepare("SELECT id, hash FROM hashes WHERE state = 0 ORDER BY id DESC LIMIT ?1;")?;
+        let mut rows = select_stmt.query([count])?;
+
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id: i32 = row.get(0)?;
+            let blob: Vec<u8> = row.get(1)?;
+            result.push((id, IdHashBinary::hash_to_string(&blob[..])));
+        }
+        Ok(result)
+    }
+
+    fn mark_complete(&mut self, ids: &[i32]) -> anyhow::Result<usize> {
+        let mut stmt = self.conn.prepare("UPDATE hashes SET state = 1 WHERE id = ?1;")?;
+        let mut count = 0;
+        for id in ids {
+            count += stmt.execute([id]).map_err(|e| anyhow!(e))?;
+        }
+        Ok(count)
+    }
+
+    fn save_handled_hash(&mut self, id: i32, hash: String) -> anyhow::Result<()> {
+        let mut stmt = self.conn.prepare("INSERT OR IGNORE INTO hashes (id, hash, state) VALUES (?1, ?2, 1)")?;
+        let blob = IdHashBinary::string_to_hash(hash)?;
+        stmt.execute(params![id, &blob])?;
+        Ok(())
+    }
+}
+
+fn insert_impl(report: DailyReport, conn: &Transaction) -> anyhow::Result<usize> {
+    let mut count = 0;
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO hashes (id, hash) VALUES (?1, ?2)")?;
+    for id_hash in report.killmails {
+        stmt.execute(params![id_hash.get_id(), &id_hash.get_hash()[..]])?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::fixtures;
+
+    fn attackers_of(conn: &Connection, id: i32) -> Vec<(Option<i32>, i32)> {
+        let mut stmt = conn
+            .prepare("SELECT character_id, damage FROM participants WHERE killmail_id = ?1 AND is_victim = 0 ORDER BY attacker_index")
+            .unwrap();
+        let rows = stmt.query_map([id], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_insert_killmails_keeps_attacker_order_and_duplicates() {
+        let mut store = SqliteKillmailStore::open(":memory:").unwrap();
+        let mut killmail = fixtures::killmail();
+        killmail.attackers.push(killmail.attackers[1].clone());
+        let expected: Vec<_> = killmail.attackers.iter().map(|a| (a.character_id, a.damage_done)).collect();
+
+        assert_eq!(store.insert_killmails(vec![killmail.clone()], Source::Esi).unwrap(), vec![97318112]);
+        assert_eq!(store.insert_killmails(vec![killmail], Source::Esi).unwrap(), vec![97318112]);
+
+        assert_eq!(attackers_of(&store.conn, 97318112), expected);
+    }
+
+    fn zkb_of(conn: &Connection, id: i32) -> (Option<String>, Option<f64>, Option<bool>, String) {
+        conn.query_row(
+            "SELECT zkb_hash, total_value, solo, source FROM killmails WHERE killmail_id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_insert_killmails_fills_in_zkb_metadata() {
+        let mut store = SqliteKillmailStore::open(":memory:").unwrap();
+        let esi = fixtures::killmail();
+        let mut websocket = esi.clone();
+        websocket.zkb = Some(fixtures::zkb());
+
+        store.insert_killmails(vec![esi], Source::Esi).unwrap();
+        assert_eq!(zkb_of(&store.conn, 97318112), (None, None, None, String::from("esi")));
+
+        store.insert_killmails(vec![websocket], Source::Websocket).unwrap();
+        let expected = (Some(fixtures::zkb().hash), Some(1402722.82), Some(true), String::from("websocket"));
+        assert_eq!(zkb_of(&store.conn, 97318112), expected);
+    }
+
+    #[test]
+    fn test_insert_killmails_keeps_zkb_metadata() {
+        let mut store = SqliteKillmailStore::open(":memory:").unwrap();
+        let esi = fixtures::killmail();
+        let mut websocket = esi.clone();
+        websocket.zkb = Some(fixtures::zkb());
+
+        store.insert_killmails(vec![websocket], Source::Websocket).unwrap();
+        store.insert_killmails(vec![esi], Source::Esi).unwrap();
+        let expected = (Some(fixtures::zkb().hash), Some(1402722.82), Some(true), String::from("websocket"));
+        assert_eq!(zkb_of(&store.conn, 97318112), expected);
+    }
+
+    #[test]
+    fn test_migrate_legacy_participants() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE killmails(
+                killmail_id INTEGER NOT NULL PRIMARY KEY,
+                killmail_time TEXT NOT NULL,
+                solar_system_id INTEGER NOT NULL
+            );
+            CREATE TABLE participants(
+                killmail_id INTEGER NOT NULL,
+                character_id INTEGER,
+                corporation_id INTEGER,
+                alliance_id INTEGER,
+                ship_type_id INTEGER,
+                damage INTEGER NOT NULL,
+                is_victim INTEGER NOT NULL,
+                UNIQUE(killmail_id, character_id, is_victim),
+                FOREIGN KEY(killmail_id) REFERENCES killmails(killmail_id)
+            );
+            INSERT INTO killmails VALUES (42, '2022-01-17T16:57:53Z', 30045314);
+            INSERT INTO participants VALUES (42, 7, 1, NULL, 670, 600, 1);
+            INSERT INTO participants VALUES (42, 3, 1, NULL, 587, 100, 0);
+            INSERT INTO participants VALUES (42, 1, 1, NULL, 587, 500, 0);
+        ").unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        assert_eq!(attackers_of(&conn, 42), vec![(Some(3), 100), (Some(1), 500)]);
+        let source: Option<String> = conn.query_row("SELECT source FROM killmails", [], |row| row.get(0)).unwrap();
+        assert_eq!(source, None);
+        let victims: i32 = conn
+            .query_row("SELECT count(*) FROM participants WHERE is_victim = 1 AND attacker_index = 0", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(victims, 1);
+    }
+
+    #[test]
+    fn test_killmails_of() {
+        let mut store = fixtures::store();
+
+        let killmails = store.killmails_of(Entity::Character(2112698901), "2021-12-01T00:00:00Z").unwrap();
+        assert_eq!(killmails.len(), 1);
+        assert_eq!(killmails[0].killmail_id, 97318112);
+        assert_eq!(killmails[0].participants.len(), 8);
+        assert_eq!(killmails[0].victim().unwrap().character_id, Some(308241937));
+        assert_eq!(killmails[0].attackers().nth(2).unwrap().character_id, Some(2116032618));
+
+        assert!(store.killmails_of(Entity::Character(2112698901), "2022-01-01T00:00:00Z").unwrap().is_empty());
+        assert!(store.killmails_of(Entity::Character(1), "2021-12-01T00:00:00Z").unwrap().is_empty());
+
+        // Two attackers of the corporation, the killmail is returned once
+        let killmails = store.killmails_of(Entity::Corporation(98676166), "2021-12-01T00:00:00Z").unwrap();
+        assert_eq!(killmails.len(), 1);
+        assert_eq!(killmails[0].attackers().filter(|p| Entity::Corporation(98676166).matches(p)).count(), 2);
+        assert_eq!(store.killmails_of(Entity::Alliance(933731581), "2021-12-01T00:00:00Z").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_killmail_times() {
+        let mut store = fixtures::store();
+
+        let kill = vec![(String::from("2021-12-12T15:46:42Z"), false)];
+        assert_eq!(store.killmail_times(Entity::Corporation(98676166), "2021-12-01T00:00:00Z").unwrap(), kill);
+        let loss = vec![(String::from("2021-12-12T15:46:42Z"), true)];
+        assert_eq!(store.killmail_times(Entity::Alliance(933731581), "2021-12-01T00:00:00Z").unwrap(), loss);
+        assert!(store.killmail_times(Entity::Character(2112698901), "2022-01-01T00:00:00Z").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_killmail_pages_and_top() {
+        let mut store = fixtures::store();
+
+        let killmail = store.killmail(97318112).unwrap().unwrap();
+        assert_eq!(killmail.participants.len(), 8);
+        assert!(store.killmail(1).unwrap().is_none());
+
+        let entity = Entity::Corporation(98676166);
+        assert_eq!(store.latest_killmails_of(entity, 0, 10).unwrap(), vec![killmail]);
+        assert!(store.latest_killmails_of(entity, 1, 10).unwrap().is_empty());
+        assert_eq!(store.latest_killmails(10).unwrap().len(), 1);
+
+        let top = store.top_attackers(Level::Corporation, "2021-12-01T00:00:00Z", 3).unwrap();
+        assert_eq!(top, vec![(1000162, 1), (98340855, 1), (98418288, 1)]);
+        let top = store.top_attackers(Level::Alliance, "2021-12-01T00:00:00Z", 10).unwrap();
+        assert!(!top.iter().any(|(id, _)| *id == 933731581));
+        assert!(store.top_attackers(Level::Character, "2022-01-01T00:00:00Z", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_opponents() {
+        let mut store = fixtures::store();
+        let since = "2021-12-01T00:00:00Z";
+
+        let corporations = Group::Level(Level::Corporation);
+        let victims = store.opponents(Entity::Alliance(99010832), Side::Victim, corporations, since, 10);
+        assert_eq!(victims.unwrap(), vec![(98052179, 1)]);
+        let characters = Group::Level(Level::Character);
+        let victims = store.opponents(Entity::Corporation(98676166), Side::Victim, characters, since, 10);
+        assert_eq!(victims.unwrap(), vec![(308241937, 1)]);
+        // The ship types of the attackers are counted once per killmail
+        let ships = store.opponents(Entity::Alliance(933731581), Side::Attacker, Group::ShipType, since, 10);
+        assert_eq!(ships.unwrap(), vec![(2212, 1), (17728, 1), (17920, 1), (47271, 1), (47466, 1)]);
+        let alliances = Group::Level(Level::Alliance);
+        let attackers = store.opponents(Entity::Alliance(933731581), Side::Attacker, alliances, since, 10);
+        assert_eq!(attackers.unwrap(), vec![(99005824, 1), (99010832, 1)]);
+
+        let victims = store.opponents(Entity::Alliance(933731581), Side::Victim, alliances, since, 10);
+        assert!(victims.unwrap().is_empty());
+        let since = "2022-01-01T00:00:00Z";
+        let ships = store.opponents(Entity::Alliance(99010832), Side::Victim, Group::ShipType, since, 10);
+        assert!(ships.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_hash_store_lifecycle() {
+        let mut store = SqliteHashStore::open(":memory:").unwrap();
+        assert_eq!(store.insert_report(fixtures::report()).unwrap(), 3);
+        let hashes = store.query_hashes(2).unwrap();
+        assert_eq!(hashes.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(hashes[1].1, "9377f28e34eabc18162e57e7e85f7a15c9339604");
+
+        assert_eq!(store.mark_complete(&[3, 2, 100]).unwrap(), 2);
+        store.save_handled_hash(4, String::from("1a38d4921711476e5ea304f799a1552b4d2e5d28")).unwrap();
+        assert_eq!(store.query_hashes(10).unwrap(), vec![(1, String::from("1a38d4921711476e5ea304f799a1552b4d2e5d28"))]);
+    }
+}
@@ -0,0 +1,485 @@
+use chrono::{DateTime, Utc};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::analytics::{since, Activity, CharacterProfile, GroupProfile, Report, Share, PERIODS};
+use crate::api::{ApiError, Database, Query};
+use crate::series::{escape, Bucket, TimeSeries};
+use crate::storage::{Entity, Level, StoredKillmail};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 0; color: #222; }
+nav { background: #263238; padding: 8px 16px; display: flex; gap: 16px; align-items: center; }
+nav a { color: #fff; text-decoration: none; }
+nav form { margin-left: auto; }
+main { padding: 8px 16px; }
+table { border-collapse: collapse; }
+th, td { text-align: left; padding: 2px 12px 2px 0; vertical-align: top; }
+tr.loss td { color: #c62828; }
+.profile th { width: 180px; }
+";
+
+/// The number of the killmails on a page
+const PAGE: usize = 20;
+
+/// The length of the top lists of a profile, the same as the API and `zkb_profile` default
+const TOP: usize = 5;
+
+/// An answer of the web pages
+pub enum Page {
+    Html(String),
+    Redirect(String),
+}
+
+/// The HTML pages over the killmail database: the search, the entity profiles and the latest kills
+pub struct Web {
+    database: Database,
+    esi: bool,
+    refresh: u32,
+}
+impl Web {
+    /// `esi` enables the names and the search by name, `refresh` is the reload period of the latest kills
+    pub fn new(database: Database, esi: bool, refresh: u32) -> Self {
+        Self { database, esi, refresh }
+    }
+
+    /// Answers the request, GET and HEAD only
+    pub async fn handle(&self, request: Request<Body>, now: DateTime<Utc>) -> Response<Body> {
+        let page = if request.method() != Method::GET && request.method() != Method::HEAD {
+            Err(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "Only GET and HEAD are supported"))
+        } else {
+            let query = Query::parse(request.uri().query());
+            self.route(request.uri().path(), &query, now).await
+        };
+        let (status, page) = match page {
+            Ok(page) => (StatusCode::OK, page),
+            Err(e) => (e.status, Page::Html(layout("Error", "", &format!("<p>{}</p>", escape(&e.message))))),
+        };
+        let builder = Response::builder().header(header::CACHE_CONTROL, "no-cache");
+        let response = match page {
+            Page::Html(html) => {
+                let builder = builder
+                    .status(status)
+                    .header(header::CONTENT_TYPE, "text/html; charset=utf-8");
+                if request.method() == Method::HEAD {
+                    builder.header(header::CONTENT_LENGTH, html.len()).body(Body::empty())
+                } else {
+                    builder.body(Body::from(html))
+                }
+            }
+            Page::Redirect(location) => builder
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, location)
+                .body(Body::empty()),
+        };
+        response.unwrap_or_else(|e| {
+            println!("Failed to build the response: {}", e);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        })
+    }
+
+    /// Answers the request of the path: `/`, `/search?q=`, `/latest` and `/{level}/{id}?days=60&page=1`
+    pub async fn route(&self, path: &str, query: &Query, now: DateTime<Utc>) -> Result<Page, ApiError> {
+        let segments: Vec<_> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match segments.as_slice() {
+            [] => self.index(now).await,
+            ["search"] => self.search(query.get("q").unwrap_or_default().trim()).await,
+            ["latest"] => self.latest().await,
+            [level, id] => match (level.parse::<Level>(), id.parse::<i32>()) {
+                (Ok(level), Ok(id)) => self.entity(level.entity(id), query, now).await,
+                _ => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Unknown page {}", path))),
+            },
+            _ => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Unknown page {}", path))),
+        }
+    }
+
+    async fn index(&self, now: DateTime<Utc>) -> Result<Page, ApiError> {
+        let since = since(now, 7);
+        let (corporations, alliances) = self
+            .database
+            .run(move |reader| {
+                let corporations = reader.top_attackers(Level::Corporation, &since, 10)?;
+                let alliances = reader.top_attackers(Level::Alliance, &since, 10)?;
+                Ok((corporations, alliances))
+            })
+            .await?;
+        let ids: Vec<_> = corporations.iter().chain(alliances.iter()).map(|(id, _)| *id).collect();
+        let names = self.names(ids).await;
+
+        let mut body = String::from("<h1>Killmails</h1>");
+        for (title, level, top) in [
+            ("Top corporations, 7 days", Level::Corporation, corporations),
+            ("Top alliances, 7 days", Level::Alliance, alliances),
+        ] {
+            body.push_str(&format!("<h2>{}</h2><table><tr><th>Name</th><th>Kills</th></tr>", title));
+            for (id, kills) in top {
+                body.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>", link(level, id, &names), kills));
+            }
+            body.push_str("</table>");
+        }
+        Ok(Page::Html(layout("Killmails", "", &body)))
+    }
+
+    /// Redirects to the page of the id or the exact name, lists the candidates if there are several
+    async fn search(&self, text: &str) -> Result<Page, ApiError> {
+        if text.is_empty() {
+            return Ok(Page::Redirect(String::from("/")));
+        }
+        let found: Vec<(Entity, Option<String>)> = if let Ok(id) = text.parse::<i32>() {
+            let entities = self
+                .database
+                .run(move |reader| {
+                    let mut found = Vec::new();
+                    for level in [Level::Character, Level::Corporation, Level::Alliance] {
+                        if !reader.latest_killmails_of(level.entity(id), 0, 1)?.is_empty() {
+                            found.push(level.entity(id));
+                        }
+                    }
+                    Ok(found)
+                })
+                .await?;
+            entities.into_iter().map(|entity| (entity, None)).collect()
+        } else if self.esi {
+            let found = crate::esi::search(text).await.map_err(|e| {
+                println!("Failed to search '{}': {}", text, e);
+                ApiError::new(StatusCode::BAD_GATEWAY, "ESI does not answer, search by id")
+            })?;
+            found.into_iter().map(|(entity, name)| (entity, Some(name))).collect()
+        } else {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "The search by name is disabled, search by id"));
+        };
+
+        match found.as_slice() {
+            [] => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Nothing found for '{}'", text))),
+            [(entity, _)] => Ok(Page::Redirect(href(*entity))),
+            _ => {
+                let mut body = format!("<h1>Search: {}</h1><ul>", escape(text));
+                for (entity, name) in &found {
+                    let name = name.clone().unwrap_or_else(|| entity.id().to_string());
+                    let item = format!(r#"<li>{} <a href="{}">{}</a></li>"#, entity.kind(), href(*entity), escape(&name));
+                    body.push_str(&item);
+                }
+                body.push_str("</ul>");
+                Ok(Page::Html(layout("Search", "", &body)))
+            }
+        }
+    }
+
+    async fn latest(&self) -> Result<Page, ApiError> {
+        let killmails = self.database.run(|reader| reader.latest_killmails(50)).await?;
+        let names = self.names(killmail_ids(&killmails)).await;
+        let head = format!(r#"<meta http-equiv="refresh" content="{}">"#, self.refresh);
+        let body = format!("<h1>Latest kills</h1>{}", killmails_table(&killmails, None, &names));
+        Ok(Page::Html(layout("Latest kills", &head, &body)))
+    }
+
+    async fn entity(&self, entity: Entity, query: &Query, now: DateTime<Utc>) -> Result<Page, ApiError> {
+        let days = query.number("days", 60, 1, 3650)?;
+        let page = query.number("page", 1, 1, 1000)?;
+        let bucket = if days > 180 { Bucket::Week } else { Bucket::Day };
+        let longest = PERIODS.iter().copied().chain(Some(days)).max().unwrap_or(days);
+        let (killmails, series, mut recent) = self
+            .database
+            .run(move |reader| {
+                let killmails = reader.killmails_of(entity, &since(now, longest))?;
+                let series = TimeSeries::query(reader, entity, now, days, bucket)?;
+                // One more to know if there is an older page
+                let recent = reader.latest_killmails_of(entity, (page - 1) * PAGE, PAGE + 1)?;
+                Ok((killmails, series, recent))
+            })
+            .await?;
+        if killmails.is_empty() && recent.is_empty() {
+            let message = format!("No killmails of the {} {}", entity.kind(), entity.id());
+            return Err(ApiError::new(StatusCode::NOT_FOUND, message));
+        }
+        let older = recent.len() > PAGE;
+        recent.truncate(PAGE);
+
+        let (title, profile) = match entity {
+            Entity::Character(id) => {
+                let mut profile = CharacterProfile::build(id, &killmails, now, days, TOP);
+                let names = self.names(profile.ids()).await;
+                profile.set_names(&names);
+                (profile.title(), character_profile(&profile))
+            }
+            _ => {
+                let mut profile = GroupProfile::build(entity, &killmails, now, days, TOP);
+                let names = self.names(profile.ids()).await;
+                profile.set_names(&names);
+                (profile.title(), group_profile(&profile))
+            }
+        };
+        let names = self.names(killmail_ids(&recent)).await;
+        let chart = series.svg(&format!("Kills and losses by {}, last {} days", bucket, days));
+
+        let mut body = format!("<h1>{} <small>{}</small></h1>", escape(&title), entity.kind());
+        body.push_str(r#"<p>Last <a href="?days=30">30</a>, <a href="?days=60">60</a>, <a href="?days=90">90</a>, <a href="?days=365">365</a> days</p>"#);
+        body.push_str(&profile);
+        body.push_str(&chart);
+        body.push_str("<h2>Killmails</h2>");
+        body.push_str(&killmails_table(&recent, Some(entity), &names));
+        if page > 1 {
+            body.push_str(&format!(r#"<a href="?days={}&page={}">Newer</a> "#, days, page - 1));
+        }
+        if older {
+            body.push_str(&format!(r#"<a href="?days={}&page={}">Older</a>"#, days, page + 1));
+        }
+        Ok(Page::Html(layout(&title, "", &body)))
+    }
+
+    /// The names of the ids if ESI is enabled and answers
+    async fn names(&self, ids: Vec<i32>) -> HashMap<i32, String> {
+        if !self.esi || ids.is_empty() {
+            return HashMap::new();
+        }
+        match crate::esi::names(&ids).await {
+            Ok(names) => names,
+            Err(e) => {
+                println!("Failed to resolve the names: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+}
+
+fn layout(title: &str, head: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>{}</title>{}<style>{}</style></head>
+<body><nav><a href="/">Home</a><a href="/latest">Latest kills</a>
+<form action="/search"><input name="q" placeholder="Name or id"> <button>Search</button></form></nav>
+<main>{}</main></body></html>
+"#,
+        escape(title),
+        head,
+        STYLE,
+        body
+    )
+}
+
+fn href(entity: Entity) -> String {
+    format!("/{}/{}", entity.kind(), entity.id())
+}
+
+fn name(id: i32, names: &HashMap<i32, String>) -> String {
+    escape(&names.get(&id).cloned().unwrap_or_else(|| id.to_string()))
+}
+
+fn link(level: Level, id: i32, names: &HashMap<i32, String>) -> String {
+    format!(r#"<a href="{}">{}</a>"#, href(level.entity(id)), name(id, names))
+}
+
+fn isk(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value >= 1e9 => format!("{:.2}B", value / 1e9),
+        Some(value) if value >= 1e6 => format!("{:.2}M", value / 1e6),
+        Some(value) if value >= 1e3 => format!("{:.1}K", value / 1e3),
+        Some(value) => format!("{:.0}", value),
+        None => String::from("-"),
+    }
+}
+
+fn row(title: &str, value: &str) -> String {
+    format!("<tr><th>{}</th><td>{}</td></tr>", title, value)
+}
+
+fn activity(activity: &[Activity], value: fn(&Activity) -> usize) -> String {
+    let counts: Vec<_> = activity
+        .iter()
+        .map(|activity| format!("{} ({} days)", value(activity), activity.days))
+        .collect();
+    counts.join(", ")
+}
+
+/// The shares joined by commas, linked to their pages if they are characters, corporations or alliances
+fn shares(shares: &[Share], level: Option<Level>) -> String {
+    if shares.is_empty() {
+        return String::from("-");
+    }
+    let shares: Vec<_> = shares
+        .iter()
+        .map(|share| {
+            let name = escape(&share.entity.to_string());
+            match level {
+                Some(level) => format!(
+                    r#"<a href="{}">{}</a> ({:.0}%)"#,
+                    href(level.entity(share.entity.id)),
+                    name,
+                    share.percent
+                ),
+                None => format!("{} ({:.0}%)", name, share.percent),
+            }
+        })
+        .collect();
+    shares.join(", ")
+}
+
+fn character_profile(profile: &CharacterProfile) -> String {
+    let optional = |level: Level, named: &Option<crate::analytics::Named>| match named {
+        Some(named) => format!(r#"<a href="{}">{}</a>"#, href(level.entity(named.id)), escape(&named.to_string())),
+        None => String::from("-"),
+    };
+    let rows = [
+        row("Corporation", &optional(Level::Corporation, &profile.corporation)),
+        row("Alliance", &optional(Level::Alliance, &profile.alliance)),
+        row("Kills", &activity(&profile.activity, |activity| activity.kills)),
+        row("Losses", &activity(&profile.activity, |activity| activity.losses)),
+        row("Probable Ship", &shares(&profile.ships, None)),
+        row("Activity System", &shares(&profile.systems, None)),
+        row("Friendly Characters", &shares(&profile.friendly_characters, Some(Level::Character))),
+        row("Friendly Corporations", &shares(&profile.friendly_corporations, Some(Level::Corporation))),
+        row("Friendly Alliances", &shares(&profile.friendly_alliances, Some(Level::Alliance))),
+    ];
+    format!(r#"<table class="profile">{}</table>"#, rows.concat())
+}
+
+fn group_profile(profile: &GroupProfile) -> String {
+    let mut rows = Vec::new();
+    if let Some(ref alliance) = profile.alliance {
+        let alliance = format!(
+            r#"<a href="{}">{}</a>"#,
+            href(Entity::Alliance(alliance.id)),
+            escape(&alliance.to_string())
+        );
+        rows.push(row("Alliance", &alliance));
+    }
+    rows.extend([
+        row("Kills", &activity(&profile.activity, |activity| activity.kills)),
+        row("Losses", &activity(&profile.activity, |activity| activity.losses)),
+        row("Active Members", &profile.active_members.to_string()),
+        row("Top Pilots", &shares(&profile.top_pilots, Some(Level::Character))),
+        row("Preferred Ships", &shares(&profile.ships, None)),
+        row("Home Systems", &shares(&profile.systems, None)),
+        row("Allied Corporations", &shares(&profile.allied_corporations, Some(Level::Corporation))),
+        row("Allied Alliances", &shares(&profile.allied_alliances, Some(Level::Alliance))),
+        row("Hostile Corporations", &shares(&profile.hostile_corporations, Some(Level::Corporation))),
+        row("Hostile Alliances", &shares(&profile.hostile_alliances, Some(Level::Alliance))),
+    ]);
+    format!(r#"<table class="profile">{}</table>"#, rows.concat())
+}
+
+/// The ids of the victims, their ships and the systems
+fn killmail_ids(killmails: &[StoredKillmail]) -> Vec<i32> {
+    let mut ids = BTreeSet::new();
+    for killmail in killmails {
+        ids.insert(killmail.solar_system_id);
+        if let Some(victim) = killmail.victim() {
+            ids.extend([victim.character_id, victim.corporation_id, victim.alliance_id, victim.ship_type_id].iter().flatten());
+        }
+    }
+    ids.into_iter().collect()
+}
+
+/// The killmails, the losses of the entity highlighted
+fn killmails_table(killmails: &[StoredKillmail], entity: Option<Entity>, names: &HashMap<i32, String>) -> String {
+    let mut table = String::from(
+        "<table><tr><th>Time</th><th>System</th><th>Victim</th><th>Ship</th><th>Value</th><th>Attackers</th><th></th></tr>",
+    );
+    for killmail in killmails {
+        let victim = killmail.victim();
+        let loss = match (entity, victim) {
+            (Some(entity), Some(victim)) => entity.matches(victim),
+            _ => false,
+        };
+        let who: Vec<_> = victim
+            .map(|victim| {
+                vec![
+                    victim.character_id.map(|id| link(Level::Character, id, names)),
+                    victim.corporation_id.map(|id| link(Level::Corporation, id, names)),
+                    victim.alliance_id.map(|id| link(Level::Alliance, id, names)),
+                ]
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect();
+        let ship = victim
+            .and_then(|victim| victim.ship_type_id)
+            .map_or(String::from("-"), |id| name(id, names));
+        table.push_str(&format!(
+            r#"<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td><a href="https://zkillboard.com/kill/{}/">zkb</a></td></tr>"#,
+            if loss { r#" class="loss""# } else { "" },
+            escape(&killmail.killmail_time.replace('T', " ").replace('Z', "")),
+            name(killmail.solar_system_id, names),
+            who.join(" / "),
+            ship,
+            isk(killmail.total_value),
+            killmail.attackers().count(),
+            killmail.killmail_id
+        ));
+    }
+    table.push_str("</table>");
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::fixtures;
+    use chrono::TimeZone;
+
+    fn web() -> Web {
+        Web::new(Database::start(Box::new(fixtures::store())), false, 30)
+    }
+
+    async fn get(web: &Web, uri: &str) -> (StatusCode, hyper::HeaderMap, String) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = web.handle(request, Utc.ymd(2021, 12, 15).and_hms(0, 0, 0)).await;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_pages() {
+        let web = web();
+        let (status, headers, html) = get(&web, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(html.contains(r#"<form action="/search">"#));
+        assert!(html.contains(r#"<a href="/corporation/98676166">98676166</a>"#));
+
+        let (status, _, html) = get(&web, "/corporation/98676166?days=30").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains("<th>Active Members</th><td>2</td>"));
+        assert!(html.contains(r#"<th>Alliance</th><td><a href="/alliance/99010832">99010832</a></td>"#));
+        assert!(html.contains("<svg "));
+        assert!(html.contains(r#"<a href="https://zkillboard.com/kill/97318112/">zkb</a>"#));
+
+        let (_, _, html) = get(&web, "/alliance/933731581").await;
+        assert!(html.contains(r#"<tr class="loss">"#));
+        let (_, _, html) = get(&web, "/character/2112698901").await;
+        assert!(html.contains("<th>Probable Ship</th><td>17920 (100%)</td>"));
+
+        let (_, _, html) = get(&web, "/latest").await;
+        assert!(html.contains(r#"<meta http-equiv="refresh" content="30">"#));
+        assert!(html.contains("2021-12-12 15:46:42"));
+
+        assert_eq!(get(&web, "/character/1").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&web, "/planet/1").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let web = web();
+        let (status, headers, _) = get(&web, "/search?q=+98676166+").await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(headers[header::LOCATION], "/corporation/98676166");
+        assert_eq!(get(&web, "/search?q=1").await.0, StatusCode::NOT_FOUND);
+        let (status, _, html) = get(&web, "/search?q=%3Cb%3E").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(html.contains("The search by name is disabled"));
+        assert_eq!(get(&web, "/search?q=").await.1[header::LOCATION], "/");
+    }
+
+    #[test]
+    fn test_isk() {
+        assert_eq!(isk(Some(1402722.82)), "1.40M");
+        assert_eq!(isk(Some(2.5e9)), "2.50B");
+        assert_eq!(isk(Some(950.0)), "950");
+        assert_eq!(isk(None), "-");
+    }
+}
@@ -0,0 +1,43 @@
+use clap::Parser;
+
+use lib::api::{Api, Database};
+use lib::server::serve;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about = "The read-only HTTP JSON API over the killmail database", version, author)]
+struct Config {
+    #[clap(
+        short,
+        long,
+        help = "Path to the database file or postgres:// URL"
+    )]
+    database: String,
+
+    #[clap(
+        long,
+        default_value_t = SocketAddr::from(([127, 0, 0, 1], 8080)),
+        help = "The address the server listens on"
+    )]
+    listen: SocketAddr,
+
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "The number of seconds the clients may cache the lists, profiles and series"
+    )]
+    max_age: u32,
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let api = Arc::new(Api::new(Database::open(&config.database)?, config.max_age));
+
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(serve(config.listen, move |request| {
+        let api = api.clone();
+        async move { api.handle(request, chrono::Utc::now()).await }
+    }))
+}
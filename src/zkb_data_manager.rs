@@ -3,9 +3,9 @@ use clap::Parser;
 use rumqttc::{Client, MqttOptions, QoS};
 use rumqttc::Event::Incoming;
 use rumqttc::Packet;
-use rusqlite::{named_params, Connection, Transaction};
 
 use lib::{CmdEvent, DataEvent, Killmail, IdHash};
+use lib::storage::{open_killmail_store, Source};
 
 use chrono::{NaiveDate, NaiveDateTime};
 use std::collections::VecDeque;
@@ -41,7 +41,7 @@ struct Config {
     #[clap(
         short,
         long,
-        help = "Path to the database file"
+        help = "Path to the database file or postgres:// URL"
     )]
     database: String,
 
@@ -67,48 +67,41 @@ fn main() -> anyhow::Result<()> {
     client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, next.clone())?;
 
     let rt = tokio::runtime::Runtime::new()?;
-    let mut conn = create_connection(&config.database)?;
-    for (_, event) in eventloop.iter().enumerate() {
+    let mut store = open_killmail_store(&config.database)?;
+    for event in eventloop.iter() {
         // println!("{:?}", event);
-        match event {
-            Ok(Incoming(Packet::Publish(event))) => {
-                let cmd: DataEvent = bincode::deserialize(event.payload.as_ref())?;
-                match cmd {
-                    DataEvent::HashesToHandle(hashes) => {
-                        println!("Received hashes to porcess {}", hashes.len());
-                        let killmails = rt.block_on(async_pre_fetch_killmails(hashes))?;
-                        println!("Received killmails to process {}", killmails.len());
-                        if acceptable(&killmails, &up_to_date) {
-                            let transaction = conn.transaction()?;
-                            let ids = fetch_and_insert(killmails, &transaction)?;
-                            transaction.commit().map_err(|e| anyhow!(format!("{}", e)))?;
-                            println!("The {} killmails updated: {:?}", ids.len(), ids);
-                            let upd: Vec<u8> = bincode::serialize(&CmdEvent::MarkComplete(ids))?;
-
-                            client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, upd.clone())?;
-                            client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, next.clone())?;
-                        } else {
-                            println!("All killmails up to {} received", up_to_date.timestamp());
-                            println!("Consider to decrease the `lower_bound` or update hashes");
-                            return Ok(());
-                        }
-                    },
-                    DataEvent::KillmailToStore(killmail) => {
-                        println!("Received killmail to porcess {} - {}", killmail.killmail_id, killmail.killmail_time);
-                        if let Some(ref zkb) = killmail.zkb {
-                            let id_hash = (killmail.killmail_id, zkb.hash.clone());
-                            let killmails = vec![killmail];
-                            let transaction = conn.transaction()?;
-                            let _ = fetch_and_insert(killmails, &transaction)?;
-                            transaction.commit().map_err(|e| anyhow!(format!("{}", e)))?;
-
-                            let upd: Vec<u8> = bincode::serialize(&CmdEvent::SaveHandledHash(id_hash))?;
-                            client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, upd.clone())?;
-                        }
+        if let Ok(Incoming(Packet::Publish(event))) = event {
+            let cmd: DataEvent = bincode::deserialize(event.payload.as_ref())?;
+            match cmd {
+                DataEvent::HashesToHandle(hashes) => {
+                    println!("Received hashes to porcess {}", hashes.len());
+                    let killmails = rt.block_on(async_pre_fetch_killmails(hashes))?;
+                    println!("Received killmails to process {}", killmails.len());
+                    if acceptable(&killmails, &up_to_date) {
+                        let ids = store.insert_killmails(killmails, Source::Esi)?;
+                        println!("The {} killmails updated: {:?}", ids.len(), ids);
+                        let upd: Vec<u8> = bincode::serialize(&CmdEvent::MarkComplete(ids))?;
+
+                        client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, upd.clone())?;
+                        client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, next.clone())?;
+                    } else {
+                        println!("All killmails up to {} received", up_to_date.timestamp());
+                        println!("Consider to decrease the `lower_bound` or update hashes");
+                        return Ok(());
+                    }
+                },
+                DataEvent::KillmailToStore(killmail) => {
+                    println!("Received killmail to porcess {} - {}", killmail.killmail_id, killmail.killmail_time);
+                    if let Some(ref zkb) = killmail.zkb {
+                        let id_hash = (killmail.killmail_id, zkb.hash.clone());
+                        let killmails = vec![killmail];
+                        let _ = store.insert_killmails(killmails, Source::Websocket)?;
+
+                        let upd: Vec<u8> = bincode::serialize(&CmdEvent::SaveHandledHash(id_hash))?;
+                        client.publish(config.cmd_topic.clone(), QoS::AtLeastOnce, false, upd.clone())?;
                     }
                 }
             }
-            _ => {}
         }
     }
     Ok(())
@@ -117,7 +110,7 @@ fn main() -> anyhow::Result<()> {
 fn acceptable(killmails: &Vec<Killmail>, up_to_date: &NaiveDateTime)->bool {
     let format = "%Y-%m-%dT%H:%M:%SZ";
     for killmail in killmails {
-        if let Some(date) = NaiveDateTime::parse_from_str(&killmail.killmail_time, format).ok() {
+        if let Ok(date) = NaiveDateTime::parse_from_str(&killmail.killmail_time, format) {
             if up_to_date.timestamp() < date.timestamp() {
                 println!("{:?} < {:?}", up_to_date, date);
                 return true;
@@ -128,7 +121,7 @@ fn acceptable(killmails: &Vec<Killmail>, up_to_date: &NaiveDateTime)->bool {
         }
     }
 
-    return false;
+    false
 }
 
 async fn async_pre_fetch_killmails(hashes: Vec<IdHash>) -> anyhow::Result<Vec<Killmail>> {
@@ -151,93 +144,6 @@ async fn async_pre_fetch_killmails(hashes: Vec<IdHash>) -> anyhow::Result<Vec<Ki
     Ok(killmails)
 }
 
-fn fetch_and_insert(killmails: Vec<Killmail>, transaction: &Transaction)-> anyhow::Result<Vec<i32>> {
-    const INSERT_KILLMAIL: &str = r"INSERT OR IGNORE INTO killmails VALUES (
-        :killmail_id,
-        :killmail_time,
-        :solar_system_id)";
-
-    const INSERT_PARTICIPANT: &str = r"INSERT OR IGNORE INTO participants VALUES (
-        :killmail_id,
-        :character_id,
-        :corporation_id,
-        :alliance_id,
-        :ship_type_id,
-        :damage,
-        :is_victim)";
-
-    let mut insert_killmail_stmt = transaction.prepare(INSERT_KILLMAIL)?;
-    let mut insert_participant_stmt = transaction.prepare(INSERT_PARTICIPANT)?;
-
-    let mut ids = Vec::new();
-    for killmail in killmails {
-        let id = killmail.killmail_id;
-
-        insert_killmail_stmt.execute(named_params! {
-            ":killmail_id": killmail.killmail_id,
-            ":killmail_time": killmail.killmail_time,
-            ":solar_system_id": killmail.solar_system_id
-        })?;
-
-        let victim = killmail.victim;
-        insert_participant_stmt.execute(named_params!{
-            ":killmail_id": killmail.killmail_id,
-            ":character_id": victim.character_id,
-            ":corporation_id": victim.corporation_id,
-            ":alliance_id": victim.alliance_id,
-            ":ship_type_id": victim.ship_type_id,
-            ":damage": victim.damage_taken,
-            ":is_victim": 1
-        })?;
-
-        for attacker in killmail.attackers {
-            insert_participant_stmt.execute(named_params!{
-                ":killmail_id": killmail.killmail_id,
-                ":character_id": attacker.character_id,
-                ":corporation_id": attacker.corporation_id,
-                ":alliance_id": attacker.alliance_id,
-                ":ship_type_id": attacker.ship_type_id,
-                ":damage": attacker.damage_done,
-                ":is_victim": 0
-            })?;
-        }
-
-        ids.push(id);
-    }
-
-    Ok(ids)
-}
-
-
-fn create_connection(url: &String) -> anyhow::Result<Connection> {
-    let conn = Connection::open(url)?;
-    let _ = conn.execute_batch("
-        PRAGMA foreign_keys = ON;
-
-        CREATE TABLE IF NOT EXISTS killmails(
-            killmail_id INTEGER NOT NULL PRIMARY KEY,
-            killmail_time TEXT NOT NULL,
-            solar_system_id INTEGER NOT NULL
-        );
-        CREATE INDEX IF NOT EXISTS killmail_time_idx ON killmails(killmail_time);
-
-        CREATE TABLE IF NOT EXISTS participants(
-            killmail_id INTEGER NOT NULL,
-            character_id INTEGER,
-            corporation_id INTEGER,
-            alliance_id INTEGER,
-            ship_type_id INTEGER,
-            damage INTEGER NOT NULL,
-            is_victim INTEGER NOT NULL,
-            UNIQUE(killmail_id, character_id, is_victim),
-            FOREIGN KEY(killmail_id) REFERENCES killmails(killmail_id)
-        );
-        CREATE INDEX IF NOT EXISTS participant_idx ON participants(character_id, corporation_id, alliance_id);
-    ").map_err(|e| anyhow!(e))?;
-
-    return Ok(conn);
-}
-
 async fn async_fetch_killmail(id: i32, hash: String) -> anyhow::Result<Killmail> {
     let url = format!("https://esi.evetech.net/latest/killmails/{}/{}/", id, hash);
     let mut response = reqwest::get(&url).await?;
@@ -255,6 +161,7 @@ async fn async_fetch_killmail(id: i32, hash: String) -> anyhow::Result<Killmail>
     }
     let text = response.text().await?;
     let maybe_killmail = serde_json::from_str::<Killmail>(&text);
-    return maybe_killmail.map_err(|e| anyhow!(format!("{}\n{}", e, text)))
+    maybe_killmail.map_err(|e| anyhow!(format!("{}\n{}", e, text)))
 }
 
+
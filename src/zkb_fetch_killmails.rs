@@ -1,28 +1,75 @@
 use anyhow::anyhow;
 use clap::Parser;
-use futures::future::join_all;
-use futures::future::TryFutureExt;
-use hyper::body::Buf;
-use hyper::Client;
-use hyper_tls::HttpsConnector;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use time::{format_description, Date};
-use tokio::task;
+use futures::future::{self, TryFutureExt};
+use futures::stream::{self, StreamExt};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS};
+use time::{format_description, Date, OffsetDateTime};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::{self, JoinHandle};
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 
-use lib::{CmdEvent, DailyReport, IdHashBinary};
+use lib::history::{daily_report, fetch_day, fetch_totals, parse_day, read_history_files, HistoryState};
+use lib::pubacks::PubAcks;
+use lib::{CmdEvent, DailyReport};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(about = "The tool for receivind killmails from the zkb api", version, author)]
 struct Config {
-    #[clap(short, long, help = "YYYY-MM-DD")]
-    first: String,
-    #[clap(short, long, help = "YYYY-MM-DD")]
-    last: String,
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = &["since", "changed-since", "days", "daemon", "import"],
+        help = "YYYY-MM-DD, today, yesterday or N days ago"
+    )]
+    first: Option<String>,
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = &["since", "changed-since", "days", "daemon", "import"],
+        help = "YYYY-MM-DD, today, yesterday or N days ago"
+    )]
+    last: Option<String>,
+    #[clap(
+        long,
+        conflicts_with_all = &["first", "last"],
+        help = "Fetch the days since YYYY-MM-DD, yesterday or N days ago up to today"
+    )]
+    since: Option<String>,
+    #[clap(
+        long,
+        conflicts_with_all = &["first", "last", "since", "force"],
+        help = "Fetch only the days since YYYY-MM-DD, yesterday or N days ago whose zKillboard totals differ from the state"
+    )]
+    changed_since: Option<String>,
+    #[clap(
+        long,
+        conflicts_with_all = &["first", "last", "since", "changed-since"],
+        help = "Fetch the last N complete days, 1 by default in the daemon mode"
+    )]
+    days: Option<i64>,
+    #[clap(
+        long,
+        conflicts_with_all = &["first", "last", "since", "changed-since", "import"],
+        help = "Fetch the last days after every UTC day rollover"
+    )]
+    daemon: bool,
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "Minutes to wait after the UTC day rollover, so zKillboard finalizes the day"
+    )]
+    delay: u64,
+    #[clap(
+        long,
+        conflicts_with_all = &["first", "last", "since", "changed-since", "days"],
+        help = "Import the history files YYYYMMDD.json from a directory or a .tar/.tar.gz/.tar.bz2 archive"
+    )]
+    import: Option<String>,
     #[clap(
         short,
         long,
@@ -44,119 +91,382 @@ struct Config {
         help = "The port of the MQTT server"
     )]
     port: u16,
+    #[clap(
+        short,
+        long,
+        default_value_t = String::from("zkb_fetch_killmails.state"),
+        help = "The file with the days already sent to the hash manager"
+    )]
+    state: String,
+    #[clap(long, help = "Fetch the days already sent to the hash manager again")]
+    force: bool,
+    #[clap(
+        long,
+        default_value_t = 3,
+        help = "The number of days fetched at the same time"
+    )]
+    concurrency: usize,
+    #[clap(
+        long,
+        default_value_t = 5,
+        help = "The number of retries of a failed history download"
+    )]
+    retries: u32,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let mut state = HistoryState::load(&config.state)?;
+    let publisher = Publisher::connect(&config);
+    let result = match config.import {
+        Some(ref path) => import(PathBuf::from(path), config.clone(), state, publisher.clone()).await,
+        None if config.daemon => daemon(config.clone(), state, publisher.clone()).await,
+        None => fetch(&config, &mut state, publisher.clone()).await,
+    };
+    publisher.shutdown().await;
+    result
+}
+
+async fn daemon(config: Config, mut state: HistoryState, publisher: Publisher) -> anyhow::Result<()> {
+    let delay = time::Duration::minutes(config.delay as i64);
+    let now = OffsetDateTime::now_utc();
+    let wake = next_wake(now, delay)?;
+    // Started before the delay after the rollover, yesterday is not finalized yet
+    if wake.date() == now.date() {
+        println!("First fetch at {}", wake);
+        sleep_until(now, wake).await;
+    }
+    loop {
+        if let Err(e) = fetch(&config, &mut state, publisher.clone()).await {
+            println!("{}", e);
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let wake = next_wake(now, delay)?;
+        println!("Next fetch at {}", wake);
+        sleep_until(now, wake).await;
+    }
+}
+
+async fn sleep_until(now: OffsetDateTime, wake: OffsetDateTime) {
+    let seconds = (wake - now).whole_seconds().max(0) as u64;
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+}
+
+/// The UTC day rollover plus the delay, today's one while it is still ahead
+fn next_wake(now: OffsetDateTime, delay: time::Duration) -> anyhow::Result<OffsetDateTime> {
+    let today = now.date().midnight().assume_utc() + delay;
+    if now < today {
+        return Ok(today);
+    }
+    let next = now
+        .date()
+        .next_day()
+        .ok_or(anyhow!(format!("No next date after {}", now.date())))?;
+    Ok(next.midnight().assume_utc() + delay)
+}
+
+/// zKillboard finalizes the history of a day `delay` after the day ends,
+/// an earlier fetch may miss the late killmails, so the day is not saved to the state
+fn is_final(day: Date, now: OffsetDateTime, delay: time::Duration) -> anyhow::Result<bool> {
+    let end = day.next_day().ok_or(anyhow!(format!("No next date after {}", day)))?;
+    Ok(now >= end.midnight().assume_utc() + delay)
+}
+
+/// The range of days to fetch, `--days` counts complete days, so it ends yesterday
+fn range(config: &Config, today: Date) -> anyhow::Result<(Date, Date)> {
+    if let Some(days) = config.days.or(if config.daemon { Some(1) } else { None }) {
+        let yesterday = today - time::Duration::days(1);
+        return Ok((yesterday - time::Duration::days(days.max(1) - 1), yesterday));
+    }
+    match config.since {
+        Some(ref since) => Ok((parse_day(since, today)?, today)),
+        None => Ok((
+            parse_day(config.first.as_deref().unwrap_or_default(), today)?,
+            parse_day(config.last.as_deref().unwrap_or_default(), today)?,
+        )),
+    }
+}
+
+async fn fetch(config: &Config, state: &mut HistoryState, publisher: Publisher) -> anyhow::Result<()> {
     let ifmt = format_description::parse("[year]-[month]-[day]")?;
     let ofmt = format_description::parse("[year][month][day]")?;
+    let now = OffsetDateTime::now_utc();
+    let today = now.date();
+    let delay = time::Duration::minutes(config.delay as i64);
 
-    let config = Config::parse();
     let mut tasks = VecDeque::new();
-    let mut current = Date::parse(&config.first, &ifmt)?;
-    let last = Date::parse(&config.last, &ifmt)?;
-    while current <= last {
-        let day = current.format(&ifmt)?;
-        let date = current.format(&ofmt)?;
-        let future = fetch_map(date).and_then(|map| handle(day, config.clone(), map));
-        tasks.push_back(future);
-        current = current
-            .next_day()
-            .ok_or(anyhow!(format!("No next date after {}", current)))?;
-    }
-
-    while !tasks.is_empty() {
-        let max_count = 3;
-        let mut pool = Vec::new();
-
-        while let Some(task) = tasks.pop_front() {
-            pool.push(task);
-            if pool.len() >= max_count {
-                break;
+    if let Some(ref since) = config.changed_since {
+        // The totals are the only source of the changes, so there is nothing to fall back to
+        let totals = fetch_totals()
+            .await
+            .map_err(|e| anyhow!("Can't fetch the history totals: {}", e))?;
+        let first = parse_day(since, today)?.format(&ifmt)?;
+        let days = state.changed_days(&totals, &first);
+        println!("{} days changed since {}", days.len(), first);
+        for day in days {
+            let date = day.replace('-', "");
+            tasks.push_back(process(day, date, config.clone(), publisher.clone()));
+        }
+    } else {
+        let totals = if config.force {
+            HashMap::new()
+        } else {
+            fetch_totals().await.unwrap_or_else(|e| {
+                println!("Can't fetch the history totals, rely on the state only: {}", e);
+                HashMap::new()
+            })
+        };
+
+        let mut skipped = 0;
+        let (mut current, last) = range(config, today)?;
+        while current <= last {
+            let day = current.format(&ifmt)?;
+            let date = current.format(&ofmt)?;
+            if !config.force && !state.is_stale(&day, totals.get(&date).copied()) {
+                skipped += 1;
+            } else {
+                tasks.push_back(process(day, date, config.clone(), publisher.clone()));
             }
+            current = current
+                .next_day()
+                .ok_or(anyhow!(format!("No next date after {}", current)))?;
         }
+        if skipped > 0 {
+            println!("Skipped {} unchanged days already sent, use --force to fetch them again", skipped);
+        }
+    }
 
-        for result in join_all(pool).await {
-            result
-                .or_else::<(), _>(|e| {
-                    println!("Future: {}", e);
-                    Ok(())
-                })
-                .unwrap();
+    let mut failed = Vec::new();
+    let mut results = stream::iter(tasks).buffer_unordered(config.concurrency.max(1));
+    while let Some((day, result)) = results.next().await {
+        match result {
+            // A day that is not finalized yet is fetched again next time
+            Ok(count) if is_final(Date::parse(&day, &ifmt)?, now, delay)? => state.save(&day, count)?,
+            Ok(_) => {}
+            Err(e) => {
+                println!("Failed {}: {}", day, e);
+                failed.push(day);
+            }
         }
     }
 
-    Ok(())
+    summary(failed)
 }
 
-async fn handle(day: String, cfg: Config, map: HashMap<i32, String>) -> anyhow::Result<()> {
-    let client_name = format!("zkb_killmail_receiver_{}", day);
-    let mut options = MqttOptions::new(client_name, &cfg.host, cfg.port);
-    options.set_keep_alive(Duration::new(5, 0));
-    let (client, mut eventloop) = AsyncClient::new(options.clone(), 100);
+async fn import(path: PathBuf, cfg: Config, mut state: HistoryState, publisher: Publisher) -> anyhow::Result<()> {
+    let ifmt = format_description::parse("[year]-[month]-[day]")?;
+    let now = OffsetDateTime::now_utc();
+    let delay = time::Duration::minutes(cfg.delay as i64);
 
-    task::spawn(async move {
-        let topic = cfg.cmd_topic.clone();
-        let res = build_report(day.clone(), map)
-            .and_then(|report| send(&client, &topic, report))
-            .await;
-        match res {
-            Ok((count, len)) => println!(
-                "Sent {} killmails for {}. The message length: {}",
-                count, day, len
-            ),
-            Err(e) => println!("Error occured {}", e),
-        }
-        client.disconnect().await
+    let (tx, mut rx) = mpsc::channel(1);
+    let reader = task::spawn_blocking(move || {
+        read_history_files(&path, |day, map| {
+            tx.blocking_send((day, map)).map_err(|e| anyhow!(format!("{}", e)))
+        })
     });
 
-    while let Some(_) = eventloop.poll().await.ok() {}
+    let mut failed = Vec::new();
+    let mut skipped = 0;
+    while let Some((day, map)) = rx.recv().await {
+        if !cfg.force && !state.is_stale(&day, Some(map.len())) {
+            skipped += 1;
+            continue;
+        }
+        match handle(day.clone(), &cfg.cmd_topic, &publisher, map).await {
+            Ok(count) if is_final(Date::parse(&day, &ifmt)?, now, delay)? => state.save(&day, count)?,
+            Ok(_) => {}
+            Err(e) => {
+                println!("Failed {}: {}", day, e);
+                failed.push(day);
+            }
+        }
+    }
+    reader.await??;
+    if skipped > 0 {
+        println!("Skipped {} unchanged days already sent, use --force to import them again", skipped);
+    }
 
-    Ok(())
+    summary(failed)
 }
 
-async fn build_report(day: String, map: HashMap<i32, String>) -> anyhow::Result<DailyReport> {
-    let mut report = DailyReport::new(day);
-    for (id, hash) in &map {
-        let id_hash = IdHashBinary::try_from((id, hash)).map_err(|err| anyhow!(err))?;
-        report.killmails.push(id_hash);
+fn summary(mut failed: Vec<String>) -> anyhow::Result<()> {
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        failed.sort();
+        Err(anyhow!("Failed {} days: {}", failed.len(), failed.join(", ")))
+    }
+}
+
+async fn process(day: String, date: String, cfg: Config, publisher: Publisher) -> (String, anyhow::Result<usize>) {
+    let result = fetch_day(&date, cfg.retries)
+        .and_then(|map| handle(day.clone(), &cfg.cmd_topic, &publisher, map))
+        .await;
+    (day, result)
+}
+
+async fn handle(day: String, topic: &str, publisher: &Publisher, map: HashMap<i32, String>) -> anyhow::Result<usize> {
+    let res = future::ready(daily_report(day.clone(), map))
+        .and_then(|report| send(publisher, topic, report))
+        .await;
+    match res {
+        Ok((count, len)) => println!(
+            "Sent {} killmails for {}. The messages length: {}",
+            count, day, len
+        ),
+        Err(ref e) => println!("Error occured {}", e),
+    }
+    res.map(|(count, _)| count)
+}
+
+/// The single MQTT connection shared by all days. A publish is confirmed
+/// by the PUBACK with its packet id, see `PubAcks`.
+#[derive(Clone)]
+struct Publisher {
+    client: AsyncClient,
+    requests: Arc<Mutex<()>>,
+    acks: Arc<StdMutex<PubAcks<oneshot::Sender<()>>>>,
+    eventloop: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+impl Publisher {
+    const MAX_INFLIGHT: u16 = 10;
+    const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn connect(cfg: &Config) -> Self {
+        let client_name = format!("zkb_fetch_killmails_{}", std::process::id());
+        let mut options = MqttOptions::new(client_name, &cfg.host, cfg.port);
+        options
+            .set_keep_alive(Duration::new(5, 0))
+            .set_inflight(Self::MAX_INFLIGHT);
+        let (client, eventloop) = AsyncClient::new(options, Self::MAX_INFLIGHT as usize);
+        let acks = Arc::new(StdMutex::new(PubAcks::default()));
+        let handle = task::spawn(Self::event_loop(eventloop, acks.clone()));
+        Self {
+            client,
+            requests: Arc::new(Mutex::new(())),
+            acks,
+            eventloop: Arc::new(Mutex::new(Some(handle))),
+        }
+    }
+
+    async fn event_loop(mut eventloop: EventLoop, acks: Arc<StdMutex<PubAcks<oneshot::Sender<()>>>>) {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => acks.lock().unwrap().sent(pkid),
+                Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                    if let Some(waiter) = acks.lock().unwrap().acked(ack.pkid) {
+                        let _ = waiter.send(());
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    // The event loop reconnects and resends the unacknowledged publishes with their packet ids
+                    println!("MQTT connection error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    /// Publishes the payload and waits for the broker to acknowledge it
+    async fn publish(&self, topic: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let confirm = async {
+            let (tx, rx) = oneshot::channel();
+            {
+                // The waiters are queued in the order the requests reach the client
+                let _requests = self.requests.lock().await;
+                self.acks.lock().unwrap().queue(tx);
+                if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, false, payload).await {
+                    self.acks.lock().unwrap().unqueue();
+                    return Err(e.into());
+                }
+            }
+            rx.await.map_err(|_| anyhow!("The MQTT event loop has stopped"))
+        };
+        tokio::time::timeout(Self::ACK_TIMEOUT, confirm)
+            .await
+            .map_err(|_| anyhow!("No PUBACK in {} secs", Self::ACK_TIMEOUT.as_secs()))?
+    }
+
+    /// Disconnects after the last acknowledged publish and waits for the event loop
+    async fn shutdown(&self) {
+        let _ = self.client.disconnect().await;
+        if let Some(handle) = self.eventloop.lock().await.take() {
+            let _ = tokio::time::timeout(Self::SHUTDOWN_TIMEOUT, handle).await;
+        }
     }
-    return Ok(report);
 }
 
 async fn send(
-    client: &AsyncClient,
-    topic: &String,
+    publisher: &Publisher,
+    topic: &str,
     report: DailyReport,
 ) -> anyhow::Result<(usize, usize)> {
     let date = report.date.clone();
     let count = report.killmails.len();
+    let mut len = 0;
 
-    let cmd = CmdEvent::SaveDailyReport(report);
-    let encoded: Vec<u8> = bincode::serialize(&cmd)?;
-    let len = encoded.len();
+    for chunk in report.split(DailyReport::CHUNK_SIZE) {
+        let cmd = CmdEvent::SaveDailyReport(chunk);
+        let encoded: Vec<u8> = bincode::serialize(&cmd)?;
+        len += encoded.len();
 
-    let mut res = client
-        .publish(topic, QoS::AtLeastOnce, false, encoded.as_slice())
-        .await;
-    if res.is_err() {
-        tokio::time::sleep(Duration::from_millis(300)).await;
-        res = client
-            .publish(topic, QoS::AtLeastOnce, false, encoded)
-            .await;
-    }
-
-    res.and_then(|()| Ok((count, len)))
-        .map_err(|e| anyhow!(format!("{} for {}", e, date)))
-}
-
-async fn fetch_map(day: String) -> anyhow::Result<HashMap<i32, String>> {
-    let url = format!("https://zkillboard.com/api/history/{}.json", day);
-    let uri = url.parse()?;
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let result = client.get(uri).await?;
-    let body = hyper::body::aggregate(result).await?;
-    let map: HashMap<i32, String> = serde_json::from_reader(body.reader())?;
-    Ok(map)
+        publisher
+            .publish(topic, encoded)
+            .await
+            .map_err(|e| anyhow!(format!("{} for {}", e, date)))?;
+    }
+
+    Ok((count, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Month, PrimitiveDateTime, Time};
+
+    fn day(d: u8) -> Date {
+        Date::from_calendar_date(2022, Month::January, d).unwrap()
+    }
+
+    fn at(d: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        PrimitiveDateTime::new(day(d), Time::from_hms(hour, minute, 0).unwrap()).assume_utc()
+    }
+
+    fn config(args: &[&str]) -> Config {
+        Config::parse_from(std::iter::once("zkb_fetch_killmails").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_range() {
+        let today = day(18);
+        assert_eq!(range(&config(&["--days", "3"]), today).unwrap(), (day(15), day(17)));
+        assert_eq!(range(&config(&["--days", "0"]), today).unwrap(), (day(17), day(17)));
+        assert_eq!(range(&config(&["--daemon"]), today).unwrap(), (day(17), day(17)));
+        assert_eq!(range(&config(&["--since", "2022-01-10"]), today).unwrap(), (day(10), day(18)));
+        let first_last = config(&["--first", "2022-01-01", "--last", "yesterday"]);
+        assert_eq!(range(&first_last, today).unwrap(), (day(1), day(17)));
+    }
+
+    #[test]
+    fn test_next_wake() {
+        let delay = time::Duration::minutes(60);
+        // Started within the delay after the rollover, the fetch waits for the finalized day
+        assert_eq!(next_wake(at(18, 0, 10), delay).unwrap(), at(18, 1, 0));
+        assert_eq!(next_wake(at(18, 1, 0), delay).unwrap(), at(19, 1, 0));
+        assert_eq!(next_wake(at(18, 23, 59), delay).unwrap(), at(19, 1, 0));
+    }
+
+    #[test]
+    fn test_is_final() {
+        let delay = time::Duration::minutes(60);
+        assert!(!is_final(day(18), at(18, 12, 0), delay).unwrap());
+        assert!(!is_final(day(17), at(18, 0, 10), delay).unwrap());
+        assert!(is_final(day(17), at(18, 1, 0), delay).unwrap());
+        assert!(is_final(day(16), at(18, 0, 10), delay).unwrap());
+    }
 }
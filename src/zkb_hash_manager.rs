@@ -3,10 +3,10 @@ use clap::Parser;
 use rumqttc::Event::Incoming;
 use rumqttc::Packet;
 use rumqttc::{Client, MqttOptions, QoS};
-use rusqlite::{params, Connection, Transaction};
 use serde::Serialize;
 
-use lib::{CmdEvent, DataEvent, DailyReport, IdHashBinary, IdHash};
+use lib::{CmdEvent, DataEvent};
+use lib::storage::open_hash_store;
 use std::collections::VecDeque;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
@@ -46,7 +46,7 @@ struct Config {
     #[clap(
         short,
         long,
-        help = "Path to the database file"
+        help = "Path to the database file or postgres:// URL"
     )]
     database: String,
 }
@@ -73,7 +73,7 @@ fn main() -> anyhow::Result<()> {
     client.subscribe(config.cmd_topic, QoS::AtMostOnce)?;
 
     let mut ready_to_exit = false;
-    for (_, event) in eventloop.iter().enumerate() {
+    for event in eventloop.iter() {
         // println!("{:?}", event);
         match event {
             Ok(Incoming(Packet::Publish(event))) => {
@@ -117,10 +117,10 @@ fn enqueue(queue: &TSharedQueue, cmd: &CmdEvent) -> bool {
     let mut lock = queue.try_lock();
     if let Ok(ref mut queue) = lock {
         queue.push_back(cmd.clone());
-        return true;
+        true
     } else {
         println!("try_lock failed in enqueue");
-        return false;
+        false
     }
 }
 
@@ -131,23 +131,11 @@ fn dequeue(queue: &TSharedQueue) -> Option<CmdEvent> {
     } else {
         println!("try_lock failed in dequeue");
     }
-    return None;
+    None
 }
 
 fn worker(queue: TSharedQueue, cond: TSharedCond, mut client: Client, cfg: Config) -> anyhow::Result<()> {
-    let url = cfg.database.clone();
-
-    let mut conn = Connection::open(url)?;
-    let _ = conn
-        .execute(
-            "CREATE TABLE IF NOT EXISTS hashes(
-            id INTEGER PRIMARY KEY NOT NULL,
-            hash BLOB NOT NULL,
-            state INTEGER NOT NULL DEFAULT 0
-        );",
-            [],
-        )
-        .map_err(|e| anyhow!(e))?;
+    let mut store = open_hash_store(&cfg.database)?;
 
     let mut ready_to_exit = false;
     while !ready_to_exit {
@@ -155,22 +143,22 @@ fn worker(queue: TSharedQueue, cond: TSharedCond, mut client: Client, cfg: Confi
         if let Some(cmd) = dequeue(&queue) {
             match cmd {
                 CmdEvent::SaveDailyReport(report) => {
-                    let date = report.date.clone();
-                    let count = insert(report, &mut conn)?;
-                    println!("Inserted {} killmails for '{}'", count, date);
+                    let (date, sequence, total) = (report.date.clone(), report.sequence, report.total);
+                    let count = store.insert_report(report)?;
+                    println!("Inserted {} killmails for '{}' ({}/{})", count, date, sequence, total);
                 },
                 CmdEvent::RequestLastHashes(count) => {
-                    let payload = query_hashes(count, &conn)?;
+                    let payload = store.query_hashes(count)?;
                     let response = DataEvent::HashesToHandle(payload);
                     publish(&mut client, &data_topic, &response)?;
                     println!("Published {} killmails for quering details", count);
                 },
                 CmdEvent::MarkComplete(ids) => {
-                    let updated = mark_complete(&ids, &conn)?;
+                    let updated = store.mark_complete(&ids)?;
                     println!("The {}/{} killmail saved: {:?}", updated, ids.len(), ids);
                 },
                 CmdEvent::SaveHandledHash((id, hash)) => {
-                    save_handled_hash(id, hash, &conn)?;
+                    store.save_handled_hash(id, hash)?;
                     println!("The {} killmail inserted as complete", id);
                 }
                 CmdEvent::Quit => {
@@ -193,51 +181,3 @@ fn publish<T: Serialize>(client: &mut Client, topic: &String, response: &T) -> a
     client.publish(topic, QoS::AtLeastOnce, false, encoded)
         .map_err(|e| anyhow!(format!("{}", e)))
 }
-
-fn insert(report: DailyReport, conn: &mut Connection) -> anyhow::Result<usize> {
-    let transaction = conn.transaction()?;
-    let count = insert_impl(report, &transaction)?;
-    transaction
-        .commit()
-        .map(|()|{count})
-        .map_err(|e| anyhow!(format!("{}", e)))
-}
-
-fn insert_impl(report: DailyReport, conn: &Transaction) -> anyhow::Result<usize> {
-    let mut count = 0;
-    let mut stmt = conn.prepare("INSERT OR IGNORE INTO hashes (id, hash) VALUES (?1, ?2)")?;
-    for id_hash in report.killmails {
-        stmt.execute(params![id_hash.get_id(), &id_hash.get_hash()[..]])?;
-        count += 1;
-    }
-    return Ok(count);
-}
-
-fn query_hashes(count: u32, conn: &Connection) -> anyhow::Result<Vec<IdHash>> {
-    let mut select_stmt = conn.prepare("SELECT id, hash FROM hashes WHERE state = 0 ORDER BY id DESC LIMIT ?1;")?;
-    let mut rows = select_stmt.query([count])?;
-
-    let mut result = Vec::new();
-    while let Some(row) = rows.next()? {
-        let id: i32 = row.get(0)?;
-        let blob: Vec<u8> = row.get(1)?;
-        result.push((id, IdHashBinary::hash_to_string(&blob[..])));
-    }
-    Ok(result)
-}
-
-fn mark_complete(ids: &Vec<i32>, conn: &Connection) -> anyhow::Result<usize> {
-    let mut stmt = conn.prepare("UPDATE hashes SET state = 1 WHERE id = ?1;")?;
-    let mut count = 0;
-    for id in ids {
-        count += stmt.execute([id]).map_err(|e| anyhow!(e))?;
-    }
-    Ok(count)
-}
-
-fn save_handled_hash(id: i32, hash: String, conn: &Connection) -> anyhow::Result<()> {
-    let mut stmt = conn.prepare("INSERT OR IGNORE INTO hashes (id, hash, state) VALUES (?1, ?2, 1)")?;
-    let blob = IdHashBinary::string_to_hash(hash)?;
-    stmt.execute(params![id, &blob])?;
-    return Ok(());
-}
\ No newline at end of file
@@ -0,0 +1,129 @@
+use clap::Parser;
+use rumqttc::{Client, Event, MqttOptions, Outgoing, QoS};
+
+use lib::archive::read_killmail_archive;
+use lib::storage::{open_killmail_store, KillmailStore, Source};
+use lib::{CmdEvent, Killmail};
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about = "The tool for importing bulk killmail archives, such as the EVE Ref dumps", version, author)]
+struct Config {
+    #[clap(
+        long,
+        default_value_t = String::from("localhost"),
+        help = "The host name of the MQTT server"
+    )]
+    host: String,
+    #[clap(
+        long,
+        default_value_t = 1883,
+        help = "The port of the MQTT server"
+    )]
+    port: u16,
+    #[clap(
+        long,
+        default_value_t = String::from(lib::CMD_TOPIC),
+        help = "MQTT topic for the commands"
+    )]
+    cmd_topic: String,
+
+    #[clap(
+        short,
+        long,
+        help = "Path to the database file or postgres:// URL"
+    )]
+    database: String,
+
+    #[clap(
+        long,
+        default_value_t = 1000,
+        help = "The number of killmails inserted in one transaction"
+    )]
+    batch: usize,
+
+    #[clap(
+        required = true,
+        help = "The .tar.bz2, .tar.gz or .tar archives with ESI killmails *.json"
+    )]
+    archives: Vec<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let client_name = format!("zkb_import_killmails_{}", std::process::id());
+    let options = MqttOptions::new(client_name, &config.host, config.port);
+    let (mut client, mut connection) = Client::new(options, 100);
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    // The connection reconnects on the next iteration
+                    println!("{:?}", e);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+        let _ = done_tx.send(());
+    });
+
+    let mut store = open_killmail_store(&config.database)?;
+    for archive in &config.archives {
+        let mut batch = Vec::with_capacity(config.batch);
+        let mut count = 0;
+        let skipped = read_killmail_archive(Path::new(archive), |killmail| {
+            batch.push(killmail);
+            if batch.len() >= config.batch {
+                count += import(&mut batch, store.as_mut(), &mut client, &config.cmd_topic)?;
+            }
+            Ok(())
+        })?;
+        count += import(&mut batch, store.as_mut(), &mut client, &config.cmd_topic)?;
+        for entry in &skipped {
+            println!("Skipped {}", entry);
+        }
+        println!("Imported {} killmails from {}, skipped {} entries", count, archive, skipped.len());
+    }
+
+    client.disconnect()?;
+    if done_rx.recv_timeout(Duration::from_secs(5)).is_err() {
+        println!("The MQTT server did not confirm the disconnect");
+    }
+    Ok(())
+}
+
+/// Inserts the batch and tells the hash manager that the killmails are complete.
+/// Killmails with zkb data carry the hash, so they are saved even if the hash manager
+/// has not received their day yet.
+fn import(
+    batch: &mut Vec<Killmail>,
+    store: &mut dyn KillmailStore,
+    client: &mut Client,
+    topic: &str,
+) -> anyhow::Result<usize> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let killmails: Vec<Killmail> = std::mem::take(batch);
+    let hashes: Vec<_> = killmails
+        .iter()
+        .filter_map(|killmail| killmail.zkb.as_ref().map(|zkb| (killmail.killmail_id, zkb.hash.clone())))
+        .collect();
+    let ids = store.insert_killmails(killmails, Source::Archive)?;
+
+    for id_hash in hashes {
+        let upd: Vec<u8> = bincode::serialize(&CmdEvent::SaveHandledHash(id_hash))?;
+        client.publish(topic, QoS::AtLeastOnce, false, upd)?;
+    }
+    let upd: Vec<u8> = bincode::serialize(&CmdEvent::MarkComplete(ids.clone()))?;
+    client.publish(topic, QoS::AtLeastOnce, false, upd)?;
+    println!("The {} killmails imported", ids.len());
+    Ok(ids.len())
+}
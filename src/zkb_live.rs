@@ -0,0 +1,98 @@
+use clap::Parser;
+use futures::future;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use tokio::time::sleep;
+
+use lib::live::Hub;
+use lib::server::serve;
+use lib::DataEvent;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about = "Pushes the live killmails from MQTT to the browsers as Server-Sent Events", version, author)]
+struct Config {
+    #[clap(
+        long,
+        default_value_t = String::from("localhost"),
+        help = "The host name of the MQTT server"
+    )]
+    host: String,
+    #[clap(
+        long,
+        default_value_t = 1883,
+        help = "The port of the MQTT server"
+    )]
+    port: u16,
+    #[clap(
+        long,
+        default_value_t = String::from(lib::DATA_TOPIC),
+        help = "MQTT topic for the killmails to store"
+    )]
+    data_topic: String,
+
+    #[clap(
+        long,
+        default_value_t = SocketAddr::from(([127, 0, 0, 1], 8082)),
+        help = "The address the server listens on"
+    )]
+    listen: SocketAddr,
+
+    #[clap(
+        long,
+        default_value_t = 1000,
+        help = "The number of killmails a client may fall behind before it skips them"
+    )]
+    capacity: usize,
+
+    #[clap(
+        long,
+        default_value_t = 15,
+        help = "The number of seconds between the keep-alive comments of an idle stream"
+    )]
+    ping: u64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let client_name = format!("zkb_live_{}", std::process::id());
+    let mut options = MqttOptions::new(client_name, &config.host, config.port);
+    // The killmails of the big fights are far above the default limit of 10KB
+    options.set_max_packet_size(1024 * 1024, 1024 * 1024);
+    let (client, eventloop) = AsyncClient::new(options, 100);
+    let hub = Hub::new(config.capacity, Duration::from_secs(config.ping));
+    let _task = tokio::task::spawn(event_loop(eventloop, client, config.data_topic.clone(), hub.clone()));
+
+    serve(config.listen, move |request| future::ready(hub.handle(request))).await
+}
+
+/// Subscribes to the data topic on every connect and passes the killmails to the hub
+async fn event_loop(mut eventloop: EventLoop, client: AsyncClient, topic: String, hub: Hub) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                if let Err(e) = client.try_subscribe(&topic, QoS::AtMostOnce) {
+                    println!("Failed to subscribe to {}: {}", topic, e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == topic => {
+                match bincode::deserialize::<DataEvent>(publish.payload.as_ref()) {
+                    Ok(DataEvent::KillmailToStore(killmail)) => {
+                        let id = killmail.killmail_id;
+                        let clients = hub.publish(killmail);
+                        println!("Killmail {} pushed to {} clients", id, clients);
+                    }
+                    Ok(DataEvent::HashesToHandle(_)) => {}
+                    Err(e) => println!("Skipped the data event: {}", e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                println!("MQTT error: {}", e);
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
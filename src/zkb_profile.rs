@@ -0,0 +1,177 @@
+use clap::Parser;
+use serde::Serialize;
+
+use lib::analytics::{since, CharacterProfile, GroupProfile, Opponents, Report, PERIODS};
+use lib::heatmap::Heatmap;
+use lib::series::{Bucket, TimeSeries};
+use lib::storage::{open_killmail_reader, Entity};
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about = "Prints the profile of a character, corporation or alliance from the killmail database", version, author)]
+struct Config {
+    #[clap(
+        short,
+        long,
+        help = "Path to the database file or postgres:// URL"
+    )]
+    database: String,
+
+    #[clap(
+        long,
+        required_unless_present_any = &["corporation", "alliance"],
+        conflicts_with_all = &["corporation", "alliance"],
+        help = "The character id"
+    )]
+    character: Option<i32>,
+
+    #[clap(
+        long,
+        conflicts_with = "alliance",
+        help = "The corporation id"
+    )]
+    corporation: Option<i32>,
+
+    #[clap(
+        long,
+        help = "The alliance id"
+    )]
+    alliance: Option<i32>,
+
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "The number of days the ships, systems, pilots and friends are based on"
+    )]
+    days: i64,
+
+    #[clap(
+        long,
+        default_value_t = 5,
+        help = "The number of the ships, systems, pilots and friends in every list"
+    )]
+    top: usize,
+
+    #[clap(
+        long,
+        default_value_t = Bucket::Day,
+        possible_values = &["hour", "day", "week"],
+        help = "The bucket of the kills and losses graphs"
+    )]
+    bucket: Bucket,
+
+    #[clap(
+        long,
+        help = "Write the kills and losses graph, or the heatmap with --heatmap, as SVG to the file"
+    )]
+    svg: Option<String>,
+
+    #[clap(
+        long,
+        help = "Print who the entity kills most and who kills it most instead of the profile"
+    )]
+    opponents: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "opponents",
+        help = "Print the killmails by weekday and hour with the estimated timezone instead of the profile"
+    )]
+    heatmap: bool,
+
+    #[clap(
+        long,
+        help = "Resolve the names with ESI"
+    )]
+    names: bool,
+
+    #[clap(
+        long,
+        help = "Print JSON instead of the text"
+    )]
+    json: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let now = chrono::Utc::now();
+    let days = PERIODS.iter().copied().chain(Some(config.days)).max().unwrap_or(config.days);
+
+    let entity = match (config.character, config.corporation, config.alliance) {
+        (Some(id), _, _) => Entity::Character(id),
+        (_, Some(id), _) => Entity::Corporation(id),
+        (_, _, Some(id)) => Entity::Alliance(id),
+        _ => unreachable!("clap requires one of the ids"),
+    };
+
+    let mut reader = open_killmail_reader(&config.database)?;
+    if config.heatmap {
+        let mut heatmap = Heatmap::query(reader.as_mut(), entity, now, config.days)?;
+        resolve_names(&mut heatmap, &config)?;
+        if let Some(ref path) = config.svg {
+            let title = format!("{}: activity by weekday and hour (UTC)", heatmap.title());
+            std::fs::write(path, heatmap.svg(&title))?;
+        }
+        if config.json {
+            println!("{}", serde_json::to_string_pretty(&heatmap)?);
+        } else {
+            print!("{}", heatmap);
+        }
+        return Ok(());
+    }
+    if config.opponents {
+        let opponents = Opponents::query(reader.as_mut(), entity, now, config.days, config.top)?;
+        let series = TimeSeries::query(reader.as_mut(), entity, now, config.days, config.bucket)?;
+        return output(opponents, series, &config);
+    }
+    let killmails = reader.killmails_of(entity, &since(now, days))?;
+    let series = TimeSeries::query(reader.as_mut(), entity, now, config.days, config.bucket)?;
+    match entity {
+        Entity::Character(id) => {
+            let profile = CharacterProfile::build(id, &killmails, now, config.days, config.top);
+            output(profile, series, &config)
+        }
+        _ => {
+            let profile = GroupProfile::build(entity, &killmails, now, config.days, config.top);
+            output(profile, series, &config)
+        }
+    }
+}
+
+/// The JSON output, the profile fields with the series next to them
+#[derive(Serialize)]
+struct Output<'a, R: Report> {
+    #[serde(flatten)]
+    profile: &'a R,
+    series: &'a TimeSeries,
+}
+
+fn resolve_names<R: Report>(report: &mut R, config: &Config) -> anyhow::Result<()> {
+    if config.names {
+        let rt = tokio::runtime::Runtime::new()?;
+        match rt.block_on(lib::esi::names(&report.ids())) {
+            Ok(names) => report.set_names(&names),
+            Err(e) => eprintln!("Failed to resolve the names: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn output<R: Report>(mut profile: R, series: TimeSeries, config: &Config) -> anyhow::Result<()> {
+    resolve_names(&mut profile, config)?;
+
+    if let Some(ref path) = config.svg {
+        let title = format!("{}: kills and losses by {}", profile.title(), series.bucket);
+        std::fs::write(path, series.svg(&title))?;
+    }
+
+    if config.json {
+        let output = Output {
+            profile: &profile,
+            series: &series,
+        };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        print!("{}{}", profile, series);
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use clap::Parser;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+
+use lib::ChannelEvent;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about = "Changes the channels of the running zkb_websocket_client", version, author)]
+struct Config {
+    #[clap(
+        long,
+        default_value_t = String::from(lib::CHANNEL_TOPIC),
+        help = "MQTT topic for the subscription changes"
+    )]
+    channel_topic: String,
+    #[clap(
+        long,
+        default_value_t = String::from("localhost"),
+        help = "The host name of the MQTT server"
+    )]
+    host: String,
+    #[clap(
+        long,
+        default_value_t = 1883,
+        help = "The port of the MQTT server"
+    )]
+    port: u16,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        help = "The channel to subscribe, such as alliance:99000001"
+    )]
+    subscribe: Vec<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        help = "The channel to unsubscribe"
+    )]
+    unsubscribe: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let client_name = format!("zkb_send_channel_{}", std::process::id());
+    let options = MqttOptions::new(client_name, &config.host, config.port);
+
+    let events = config
+        .subscribe
+        .iter()
+        .cloned()
+        .map(ChannelEvent::Subscribe)
+        .chain(config.unsubscribe.iter().cloned().map(ChannelEvent::Unsubscribe));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    for event in events {
+        let encoded: Vec<u8> = bincode::serialize(&event)?;
+        client.publish(&config.channel_topic, QoS::AtLeastOnce, false, encoded).await?;
+    }
+    client.disconnect().await?;
+    while eventloop.poll().await.is_ok() {}
+    Ok(())
+}
@@ -40,6 +40,6 @@ async fn main() -> anyhow::Result<()> {
     let (client, mut eventloop) = AsyncClient::new(options, 100);
     client.publish(config.cmd_topic, QoS::AtLeastOnce, false, encoded).await?;
     client.disconnect().await?;
-    while let Some(_) = eventloop.poll().await.ok() {}
+    while eventloop.poll().await.is_ok() {}
     Ok(())
 }
@@ -0,0 +1,50 @@
+use clap::Parser;
+
+use lib::api::Database;
+use lib::server::serve;
+use lib::web::Web;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about = "The web pages over the killmail database: search, profiles and the latest kills", version, author)]
+struct Config {
+    #[clap(
+        short,
+        long,
+        help = "Path to the database file or postgres:// URL"
+    )]
+    database: String,
+
+    #[clap(
+        long,
+        default_value_t = SocketAddr::from(([127, 0, 0, 1], 8081)),
+        help = "The address the server listens on"
+    )]
+    listen: SocketAddr,
+
+    #[clap(
+        long,
+        help = "Resolve the names and search by name with ESI"
+    )]
+    esi: bool,
+
+    #[clap(
+        long,
+        default_value_t = 30,
+        help = "The number of seconds between the reloads of the latest kills page"
+    )]
+    refresh: u32,
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    let web = Arc::new(Web::new(Database::open(&config.database)?, config.esi, config.refresh));
+
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(serve(config.listen, move |request| {
+        let web = web.clone();
+        async move { web.handle(request, chrono::Utc::now()).await }
+    }))
+}
@@ -1,10 +1,32 @@
-use clap::Parser;
-use websockets::{WebSocket, Frame, WebSocketError};
+use anyhow::anyhow;
+use clap::{ArgEnum, Parser};
+use websockets::{Frame, WebSocket, WebSocketReadHalf, WebSocketWriteHalf, WebSocketError};
 
-use rumqttc::{AsyncClient, MqttOptions, QoS, EventLoop};
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS, EventLoop};
 use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::time::{sleep, Duration, Instant};
 
-use lib::{Killmail, DataEvent};
+use lib::history::{daily_report, fetch_day, missed_days};
+use lib::pubacks::PubAcks;
+use lib::redisq::RedisQ;
+use lib::spool::Spool;
+use lib::{ChannelEvent, CmdEvent, DailyReport, Killmail, DataEvent};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Websocket,
+    Redisq,
+}
 
 #[derive(Parser, Debug, Clone)]
 #[clap(about, version, author)]
@@ -27,6 +49,104 @@ struct Config {
         help = "MQTT topic for the data"
     )]
     data_topic: String,
+    #[clap(
+        long,
+        default_value_t = String::from(lib::KILLS_TOPIC),
+        help = "The prefix of the alliance, corporation, character and system topics of the killmails"
+    )]
+    kills_topic: String,
+    #[clap(
+        long,
+        default_value_t = String::from(lib::CMD_TOPIC),
+        help = "MQTT topic for the commands"
+    )]
+    cmd_topic: String,
+    #[clap(
+        long,
+        default_value_t = String::from(lib::CHANNEL_TOPIC),
+        help = "MQTT topic for the subscription changes at runtime"
+    )]
+    channel_topic: String,
+    #[clap(
+        long = "channel",
+        multiple_occurrences = true,
+        default_value = "killstream",
+        help = "The zKillboard channel, such as killstream, alliance:<id>, corporation:<id>, character:<id>, system:<id> or region:<id>"
+    )]
+    channels: Vec<String>,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "websocket",
+        help = "Where the live killmails come from"
+    )]
+    source: Source,
+    #[clap(
+        long,
+        default_value_t = String::from("wss://zkillboard.com/websocket/"),
+        help = "The zKillboard websocket URL"
+    )]
+    url: String,
+    #[clap(
+        long,
+        default_value_t = String::from(RedisQ::URL),
+        help = "The zKillboard RedisQ URL"
+    )]
+    redisq_url: String,
+    #[clap(
+        long,
+        required_if_eq("source", "redisq"),
+        help = "The RedisQ queue id, zKillboard keeps the position of the queue across restarts"
+    )]
+    queue_id: Option<String>,
+    #[clap(
+        long,
+        default_value_t = 10,
+        help = "Seconds RedisQ waits for a killmail before an empty answer"
+    )]
+    ttw: u64,
+    #[clap(
+        long,
+        default_value_t = 30,
+        help = "Seconds between the websocket pings"
+    )]
+    ping: u64,
+    #[clap(
+        long,
+        default_value_t = 120,
+        help = "Seconds without any frame after which the websocket is reconnected"
+    )]
+    stale: u64,
+    #[clap(
+        long,
+        default_value_t = String::from("zkb_websocket_client.spool"),
+        help = "The file that keeps the killmails and the commands until the MQTT server acknowledges them"
+    )]
+    spool: String,
+    #[clap(
+        long,
+        default_value_t = 256,
+        help = "The maximal size of the spool file in MB, newer killmails are dropped above it"
+    )]
+    spool_size: u64,
+    #[clap(
+        long,
+        default_value_t = String::from("zkb_websocket_client.last"),
+        help = "The file with the time of the last received killmail"
+    )]
+    last_seen: String,
+    #[clap(
+        long,
+        default_value_t = 3,
+        help = "The maximal number of the latest days fetched from the history after a reconnect, 0 disables the backfill"
+    )]
+    backfill_days: usize,
+    #[clap(
+        long,
+        default_value_t = 15,
+        help = "Minutes without killmails before a reconnect backfills, a day is fetched only if it misses that long"
+    )]
+    backfill_gap: i64,
 }
 
 #[tokio::main]
@@ -35,47 +155,575 @@ async fn main() -> anyhow::Result<()> {
     let client_name = "zkb_websocket_client";
     let options = MqttOptions::new(client_name, &config.host, config.port);
 
-    let mut ws = WebSocket::connect("wss://zkillboard.com/websocket/").await?;
-    ws.send_text(r#"{"action":"sub","channel":"killstream"}"#.to_string()).await?;
+    // A killmail is published to dozens of topics at once
+    let (client, eventloop) = AsyncClient::new(options, 1000);
+    let spool = Spool::open(&config.spool, config.spool_size * 1024 * 1024)?;
+    if !spool.is_empty() {
+        println!("Spool {}: {}", spool.path().display(), spool.stats());
+    }
+    let publisher = Arc::new(Publisher {
+        client,
+        connected: AtomicBool::new(false),
+        spool: Mutex::new(spool),
+        acks: Mutex::new(PubAcks::default()),
+    });
+    let (commands_tx, commands) = mpsc::unbounded_channel();
+    let _task = tokio::task::spawn(event_loop(
+        eventloop,
+        publisher.clone(),
+        config.channel_topic.clone(),
+        commands_tx,
+    ));
+    let _drain = tokio::task::spawn(drain(publisher.clone()));
 
-    let (client, eventloop) = AsyncClient::new(options, 100);
-    let topic = &config.data_topic;
-    let _task = tokio::task::spawn(event_loop(eventloop));
+    let mut last_seen = LastSeen::load(&config.last_seen)?;
+    let backfill = Backfill {
+        running: Arc::new(AtomicBool::new(false)),
+        publisher: publisher.clone(),
+        topic: config.cmd_topic.clone(),
+        max_days: config.backfill_days,
+        min_gap: time::Duration::minutes(config.backfill_gap),
+    };
+    match config.source {
+        Source::Websocket => websocket(&config, &publisher, &backfill, commands, &mut last_seen).await,
+        Source::Redisq => redisq(&config, &publisher, &backfill, &mut last_seen).await,
+    }
+}
 
+async fn websocket(
+    config: &Config,
+    publisher: &Publisher,
+    backfill: &Backfill,
+    mut commands: mpsc::UnboundedReceiver<ChannelEvent>,
+    last_seen: &mut LastSeen,
+) -> anyhow::Result<()> {
+    let mut channels: BTreeSet<String> = config.channels.iter().cloned().collect();
+    let mut backoff = MIN_BACKOFF;
     loop {
-        let maybe_response = ws.receive().await;
-        match maybe_response {
-            // println!("{:?}", response);
-            Ok(response) => {
-                if let Frame::Text{payload, continuation, fin} = response {
-                    if !continuation && fin {
-                        let killmail = serde_json::from_str::<Killmail>(&payload)?;
-                        let id = killmail.killmail_id;
-                        let cmd = DataEvent::KillmailToStore(killmail);
-                        let encoded: Vec<u8> = bincode::serialize(&cmd)?;
-                        client.publish(topic, QoS::AtLeastOnce, false, encoded).await?;
-                        let now: DateTime<Utc> = Utc::now();
-                        println!("published {} - {}", id, now.format("%a %b %e %T"));
-                    }
+        // The changes received while disconnected are applied by the next subscription
+        while let Ok(event) = commands.try_recv() {
+            apply(&mut channels, event);
+        }
+        match connect(&config.url, &channels).await {
+            Ok(ws) => {
+                println!("Connected to {}: {:?}", config.url, channels);
+                backfill.start(last_seen);
+                let (read, mut write) = ws.split();
+                let mut session = Session {
+                    channels: &mut channels,
+                    commands: &mut commands,
+                    last_seen,
+                    backoff: &mut backoff,
+                };
+                if let Err(e) = listen(read, &mut write, publisher, config, &mut session).await {
+                    println!("Websocket error: {}", e);
+                }
+                let _ = write.shutdown().await;
+            }
+            Err(e) => println!("Failed to connect to {}: {:?}", config.url, e),
+        }
+        println!("Reconnect in {} secs", backoff.as_secs());
+        sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// Polls RedisQ, the missed days are backfilled at the start and after the errors.
+/// RedisQ has no channels, every killmail of the queue is published.
+async fn redisq(
+    config: &Config,
+    publisher: &Publisher,
+    backfill: &Backfill,
+    last_seen: &mut LastSeen,
+) -> anyhow::Result<()> {
+    let queue_id = config.queue_id.as_deref().ok_or_else(|| anyhow!("--queue-id is required for RedisQ"))?;
+    let queue = RedisQ::new(&config.redisq_url, queue_id, config.ttw)?;
+    println!("Polling {} as '{}'", config.redisq_url, queue_id);
+    let mut backoff = MIN_BACKOFF;
+    let mut recovered = true;
+    loop {
+        match queue.next().await {
+            Ok(killmail) => {
+                if recovered {
+                    backfill.start(last_seen);
+                    recovered = false;
+                }
+                backoff = MIN_BACKOFF;
+                if let Some(killmail) = killmail {
+                    publish(killmail, publisher, config, last_seen);
                 }
             }
             Err(e) => {
-                println!("Error: {:?}", e);
-                if let WebSocketError::ReadError(_) = e {
-                    ws.close(None).await?;
-                    ws = WebSocket::connect("wss://zkillboard.com/websocket/").await?;
-                    ws.send_text(r#"{"action":"sub","channel":"killstream"}"#.to_string()).await?;
+                println!("RedisQ error: {}", e);
+                println!("Retry in {} secs", backoff.as_secs());
+                recovered = true;
+                sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+async fn connect(url: &str, channels: &BTreeSet<String>) -> Result<WebSocket, WebSocketError> {
+    let mut ws = WebSocket::connect(url).await?;
+    for channel in channels {
+        ws.send_text(message("sub", channel)).await?;
+    }
+    Ok(ws)
+}
+
+fn message(action: &str, channel: &str) -> String {
+    serde_json::json!({ "action": action, "channel": channel }).to_string()
+}
+
+/// Updates the subscriptions and returns the websocket message of the change, if any
+fn apply(channels: &mut BTreeSet<String>, event: ChannelEvent) -> Option<String> {
+    match event {
+        ChannelEvent::Subscribe(channel) if channels.insert(channel.clone()) => {
+            println!("Subscribed to {}", channel);
+            Some(message("sub", &channel))
+        }
+        ChannelEvent::Unsubscribe(channel) if channels.remove(&channel) => {
+            println!("Unsubscribed from {}", channel);
+            Some(message("unsub", &channel))
+        }
+        _ => None,
+    }
+}
+
+/// The state of the client that outlives a websocket connection
+struct Session<'a> {
+    channels: &'a mut BTreeSet<String>,
+    commands: &'a mut mpsc::UnboundedReceiver<ChannelEvent>,
+    last_seen: &'a mut LastSeen,
+    backoff: &'a mut Duration,
+}
+
+/// The time of the latest killmail received from the websocket, the missed window
+/// after a reconnect starts there
+struct LastSeen {
+    path: PathBuf,
+    time: Option<OffsetDateTime>,
+}
+impl LastSeen {
+    /// Loads the saved time, a damaged file is ignored, so the next killmail replaces it
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from(path);
+        let time = match std::fs::read_to_string(&path) {
+            Ok(time) if !time.trim().is_empty() => match OffsetDateTime::parse(time.trim(), &Rfc3339) {
+                Ok(time) => Some(time),
+                Err(e) => {
+                    println!("Ignored the last seen time in {}: {}", path.display(), e);
+                    None
+                }
+            },
+            Ok(_) => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(anyhow!("{}: {}", path.display(), e)),
+        };
+        Ok(Self { path, time })
+    }
+
+    /// Keeps the latest killmail time, a time that is not RFC 3339 is skipped
+    fn update(&mut self, killmail_time: &str) {
+        let time = match OffsetDateTime::parse(killmail_time, &Rfc3339) {
+            Ok(time) => time,
+            Err(e) => {
+                println!("Skipped the killmail time '{}': {}", killmail_time, e);
+                return;
+            }
+        };
+        if self.time.is_none_or(|last| last < time) {
+            self.time = Some(time);
+            if let Err(e) = std::fs::write(&self.path, killmail_time) {
+                println!("Failed to save {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    /// The days (YYYY-MM-DD) that miss at least `min_gap` of the window from the last killmail to `now`
+    fn missed_days(&self, now: OffsetDateTime, min_gap: time::Duration, max: usize) -> Vec<String> {
+        match self.time {
+            Some(last) if max > 0 => missed_days(last, now, min_gap, max).iter().map(|day| day.to_string()).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Feeds the history of the missed days to the hash pipeline: the reports are saved
+/// by the hash manager, the running request chain of the data manager fetches the new hashes
+struct Backfill {
+    running: Arc<AtomicBool>,
+    publisher: Arc<Publisher>,
+    topic: String,
+    max_days: usize,
+    min_gap: time::Duration,
+}
+impl Backfill {
+    const RETRIES: u32 = 5;
+
+    /// Starts fetching the days since the last killmail, unless a backfill is running already
+    fn start(&self, last_seen: &LastSeen) {
+        let days = last_seen.missed_days(OffsetDateTime::now_utc(), self.min_gap, self.max_days);
+        if days.is_empty() || self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (running, publisher, topic) = (self.running.clone(), self.publisher.clone(), self.topic.clone());
+        tokio::task::spawn(async move {
+            Self::run(days, &publisher, &topic).await;
+            running.store(false, Ordering::SeqCst);
+        });
+    }
+
+    async fn run(days: Vec<String>, publisher: &Publisher, topic: &str) {
+        println!("Backfill {:?}", days);
+        for day in days {
+            let report = match fetch_day(&day.replace('-', ""), Self::RETRIES).await {
+                Ok(map) => daily_report(day.clone(), map),
+                Err(e) => Err(e),
+            };
+            let report = match report {
+                Ok(report) => report,
+                Err(e) => {
+                    println!("Failed to backfill {}: {}", day, e);
+                    continue;
+                }
+            };
+            let count = report.killmails.len();
+            for chunk in report.split(DailyReport::CHUNK_SIZE) {
+                send_command(publisher, topic, &CmdEvent::SaveDailyReport(chunk));
+            }
+            println!("Backfilled {} killmails for {}", count, day);
+        }
+    }
+}
+
+fn send_command(publisher: &Publisher, topic: &str, cmd: &CmdEvent) {
+    let result = bincode::serialize(cmd)
+        .map_err(|e| anyhow!(e))
+        .and_then(|encoded| publisher.send(topic, encoded));
+    match result {
+        Ok(Delivery::Dropped) => println!("Dropped the command, the spool is full"),
+        Ok(_) => {}
+        Err(e) => println!("Failed to send the command: {}", e),
+    }
+}
+
+/// Publishes the killmails of the connection until it fails or goes stale.
+/// The frames are read in a separate task, so a ping never interrupts a partially read frame.
+async fn listen(
+    mut read: WebSocketReadHalf,
+    write: &mut WebSocketWriteHalf,
+    publisher: &Publisher,
+    cfg: &Config,
+    session: &mut Session<'_>,
+) -> anyhow::Result<()> {
+    let (tx, mut rx) = mpsc::channel(100);
+    let reader = tokio::task::spawn(async move {
+        loop {
+            let frame = read.receive().await;
+            let failed = frame.is_err();
+            if tx.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let stale = Duration::from_secs(cfg.stale);
+    let mut ping = tokio::time::interval(Duration::from_secs(cfg.ping));
+    let mut last_seen = Instant::now();
+    let result = loop {
+        tokio::select! {
+            frame = rx.recv() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => break Err(anyhow!("{:?}", e)),
+                    None => break Err(anyhow!("The reader has stopped")),
+                };
+                last_seen = Instant::now();
+                *session.backoff = MIN_BACKOFF;
+                match frame {
+                    Frame::Text { payload, continuation: false, fin: true } => {
+                        match serde_json::from_str::<Killmail>(&payload) {
+                            Ok(killmail) => publish(killmail, publisher, cfg, session.last_seen),
+                            Err(e) => {
+                                let head: String = payload.chars().take(200).collect();
+                                println!("Skipped the frame: {}: {}", e, head);
+                            }
+                        }
+                    }
+                    Frame::Close { payload } => break Err(anyhow!("Closed by the server: {:?}", payload)),
+                    _ => {}
+                }
+            }
+            Some(event) = session.commands.recv() => {
+                if let Some(text) = apply(session.channels, event) {
+                    if let Err(e) = write.send_text(text).await {
+                        break Err(anyhow!("{:?}", e));
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > stale {
+                    break Err(anyhow!("No frames for {} secs", last_seen.elapsed().as_secs()));
+                }
+                // Sends the queued pongs as well
+                if let Err(e) = write.send(Frame::Ping { payload: None }).await {
+                    break Err(anyhow!("{:?}", e));
                 }
             }
         }
+    };
+    reader.abort();
+    result
+}
+
+/// Publishes the killmail to the data topic for the data manager and to the topics of its participants,
+/// the failures are logged and skipped, so they never stop the client
+fn publish(killmail: Killmail, publisher: &Publisher, cfg: &Config, last_seen: &mut LastSeen) {
+    let id = killmail.killmail_id;
+    let time = killmail.killmail_time.clone();
+    let topics = killmail.topics(&cfg.kills_topic);
+    let fan_out = bincode::serialize(&killmail);
+    let cmd = DataEvent::KillmailToStore(killmail);
+    let (encoded, fan_out) = match (bincode::serialize(&cmd), fan_out) {
+        (Ok(encoded), Ok(fan_out)) => (encoded, fan_out),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Failed to encode {}: {}", id, e);
+            return;
+        }
+    };
+    let now: DateTime<Utc> = Utc::now();
+    match publisher.send(&cfg.data_topic, encoded) {
+        Ok(Delivery::Published) => {
+            last_seen.update(&time);
+            println!("published {} - {}", id, now.format("%a %b %e %T"));
+        }
+        Ok(Delivery::Spooled) => {
+            last_seen.update(&time);
+            println!("spooled {} - {}", id, now.format("%a %b %e %T"));
+        }
+        Ok(Delivery::Dropped) => println!("Dropped {}, the spool is full", id),
+        Err(e) => println!("Failed to spool {}: {}", id, e),
     }
-    // ws.close(None).await?;
-    // client.disconnect().await?;
-    // task.await?;
+    let dropped = topics
+        .iter()
+        .filter(|topic| !publisher.send_copy(topic, fan_out.clone()))
+        .count();
+    if dropped > 0 {
+        println!("Dropped {} of {} topic copies of {}", dropped, topics.len(), id);
+    }
+}
 
-    // Ok(())
+enum Delivery {
+    Published,
+    Spooled,
+    Dropped,
 }
 
-async fn event_loop(mut eventloop: EventLoop) {
-    while let Some(_) = eventloop.poll().await.ok() {}
-}
\ No newline at end of file
+/// Every data and command message goes through the spool and stays there until
+/// the MQTT server acknowledges it, so the messages survive an outage and a restart
+/// and reach the broker in the order they were received
+struct Publisher {
+    client: AsyncClient,
+    connected: AtomicBool,
+    spool: Mutex<Spool>,
+    /// The spool sequence numbers of the publishes, None for the topic copies
+    acks: Mutex<PubAcks<Option<u64>>>,
+}
+impl Publisher {
+    fn send(&self, topic: &str, payload: Vec<u8>) -> anyhow::Result<Delivery> {
+        let record = bincode::serialize(&(topic, payload))?;
+        let mut spool = self.spool.lock().unwrap();
+        if !spool.push(&record)? {
+            return Ok(Delivery::Dropped);
+        }
+        self.drain_spool(&mut spool)?;
+        if spool.unsent() == 0 {
+            Ok(Delivery::Published)
+        } else {
+            Ok(Delivery::Spooled)
+        }
+    }
+
+    /// Publishes a copy for the topic of a participant. The copies are not spooled,
+    /// they are dropped while the MQTT server is unreachable, returns false then.
+    fn send_copy(&self, topic: &str, payload: Vec<u8>) -> bool {
+        if !self.connected.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut acks = self.acks.lock().unwrap();
+        acks.queue(None);
+        if self.client.try_publish(topic, QoS::AtLeastOnce, false, payload).is_err() {
+            acks.unqueue();
+            return false;
+        }
+        true
+    }
+
+    /// Publishes the spooled messages that were not sent yet
+    fn drain(&self) -> anyhow::Result<usize> {
+        let mut spool = self.spool.lock().unwrap();
+        self.drain_spool(&mut spool)
+    }
+
+    /// Publishes until the request queue of the client is full, the messages stay
+    /// in the spool until `acked`
+    fn drain_spool(&self, spool: &mut Spool) -> anyhow::Result<usize> {
+        if spool.unsent() == 0 || !self.connected.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        let mut broken = Vec::new();
+        let mut acks = self.acks.lock().unwrap();
+        let count = spool.drain(|seq, record| match bincode::deserialize::<(String, Vec<u8>)>(record) {
+            Ok((topic, payload)) => {
+                acks.queue(Some(seq));
+                let queued = self.client.try_publish(topic, QoS::AtLeastOnce, false, payload).is_ok();
+                if !queued {
+                    acks.unqueue();
+                }
+                queued
+            }
+            Err(e) => {
+                println!("Skipped the spooled record: {}", e);
+                broken.push(seq);
+                true
+            }
+        })?;
+        drop(acks);
+        for seq in broken {
+            spool.ack(seq)?;
+        }
+        Ok(count)
+    }
+
+    /// Removes the acknowledged message from the spool
+    fn acked(&self, pkid: u16) -> anyhow::Result<()> {
+        let seq = self.acks.lock().unwrap().acked(pkid);
+        if let Some(Some(seq)) = seq {
+            self.spool.lock().unwrap().ack(seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drains the spool and reports its counters every minute while they change
+async fn drain(publisher: Arc<Publisher>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut reported = publisher.spool.lock().unwrap().stats().clone();
+    let mut report = Instant::now();
+    loop {
+        interval.tick().await;
+        match publisher.drain() {
+            Ok(0) => {}
+            Ok(count) => println!("Sent {} spooled messages", count),
+            Err(e) => println!("Failed to drain the spool: {}", e),
+        }
+        if report.elapsed() >= Duration::from_secs(60) {
+            let stats = publisher.spool.lock().unwrap().stats().clone();
+            if stats != reported {
+                println!("Spool: {}", stats);
+                reported = stats;
+            }
+            report = Instant::now();
+        }
+    }
+}
+
+/// Keeps the MQTT connection alive, the requests are queued while it reconnects.
+/// The channel topic is subscribed again on every connection, the session is not persistent.
+async fn event_loop(
+    mut eventloop: EventLoop,
+    publisher: Arc<Publisher>,
+    topic: String,
+    commands: mpsc::UnboundedSender<ChannelEvent>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                publisher.connected.store(true, Ordering::SeqCst);
+                if let Err(e) = publisher.client.try_subscribe(&topic, QoS::AtLeastOnce) {
+                    println!("Failed to subscribe to {}: {}", topic, e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == topic => {
+                match bincode::deserialize::<ChannelEvent>(publish.payload.as_ref()) {
+                    Ok(event) => {
+                        let _ = commands.send(event);
+                    }
+                    Err(e) => println!("Skipped the channel command: {}", e),
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(pkid))) => publisher.acks.lock().unwrap().sent(pkid),
+            Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                if let Err(e) = publisher.acked(ack.pkid) {
+                    println!("Failed to update the spool: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // The unacknowledged publishes are resent after the reconnect
+                publisher.connected.store(false, Ordering::SeqCst);
+                println!("MQTT error: {}", e);
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("zkb_tools_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn at(value: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(value, &Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn test_last_seen_persistence() {
+        let path = temp_path("last_seen");
+        let mut last_seen = LastSeen::load(&path).unwrap();
+        assert_eq!(last_seen.time, None);
+
+        last_seen.update("2022-01-17T10:00:00Z");
+        // An older killmail delivered late keeps the latest time
+        last_seen.update("2022-01-17T09:00:00Z");
+        // A broken time is never saved
+        last_seen.update("9999-99-99");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "2022-01-17T10:00:00Z");
+
+        let last_seen = LastSeen::load(&path).unwrap();
+        assert_eq!(last_seen.time, Some(at("2022-01-17T10:00:00Z")));
+
+        // A damaged file is ignored
+        std::fs::write(&path, "yesterday").unwrap();
+        assert_eq!(LastSeen::load(&path).unwrap().time, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missed_days() {
+        let path = temp_path("missed_days");
+        let gap = time::Duration::minutes(15);
+        let mut last_seen = LastSeen::load(&path).unwrap();
+        // Nothing was seen yet, so nothing is missing
+        assert!(last_seen.missed_days(at("2022-01-17T12:00:00Z"), gap, 3).is_empty());
+
+        last_seen.update("2022-01-16T23:00:00Z");
+        let days = |now| last_seen.missed_days(at(now), gap, 3);
+        assert!(days("2022-01-16T23:01:00Z").is_empty());
+        assert_eq!(days("2022-01-16T23:30:00Z"), vec!["2022-01-16"]);
+        assert_eq!(days("2022-01-17T00:10:00Z"), vec!["2022-01-16"]);
+        assert_eq!(days("2022-01-17T12:00:00Z"), vec!["2022-01-16", "2022-01-17"]);
+        assert_eq!(days("2022-01-20T12:00:00Z"), vec!["2022-01-18", "2022-01-19", "2022-01-20"]);
+        // The backfill is disabled
+        assert!(last_seen.missed_days(at("2022-01-20T12:00:00Z"), gap, 0).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}